@@ -0,0 +1,5849 @@
+//! Folder-based command cache. The command line tool is a thin wrapper around [`run`], while
+//! [`compute_key`], [`try_restore`] and [`store`] let other programs cache runs without
+//! shelling out.
+
+use chrono::format::{Item, StrftimeItems};
+use color_eyre::eyre::{eyre, Report, Result, WrapErr};
+use filetime::FileTime;
+use flate2::read::GzDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use fs2::FileExt;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use gzp::deflate::Gzip;
+use gzp::ZBuilder;
+use ignore::{overrides::OverrideBuilder, WalkBuilder};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{info, trace, warn};
+use rayon::prelude::*;
+use regex::bytes::Regex;
+use serde::{Deserialize, Serialize};
+use simplelog::{ColorChoice, ConfigBuilder, TermLogger, TerminalMode};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::str::FromStr;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+use structopt::clap::{ArgMatches, Error as ClapError, ErrorKind as ClapErrorKind};
+use structopt::StructOpt;
+use walkdir::WalkDir;
+
+/// Runs folca as the command line tool does, exiting the process with the command's exit code
+/// when it fails
+pub fn run() -> Result<()> {
+    let opt = Config::from_args_and_config()?;
+    if let Some((description, kind)) = opt.usage_error() {
+        ClapError::with_description(description, kind).exit();
+    }
+    let level = match opt.verbose {
+        0 => log::LevelFilter::Warn,
+        1 => log::LevelFilter::Info,
+        2 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
+    match opt.log_format {
+        LogFormat::Text => TermLogger::init(
+            level,
+            opt.logger_config(),
+            TerminalMode::Mixed,
+            opt.color.choice(),
+        )?,
+        LogFormat::Json => JsonLogger::init(level)?,
+    }
+
+    if let Some(config_file) = &opt.config_file {
+        info!("Using configuration from {}", config_file.to_string_lossy());
+    }
+    trace!("{:#?}", opt);
+
+    if opt.print_key {
+        let key = compute_key(&opt)?;
+        println!("{:x}/{:016x}", key.command_hash, key.input_hash);
+        return Ok(());
+    }
+
+    let mut inventory = Inventory::open(&opt)?;
+
+    if let Some(subcommand) = &opt.subcommand {
+        return match subcommand {
+            Subcommand::Stats {
+                json, hits: true, ..
+            } => {
+                let _lock = inventory.lock(false, opt.lock_timeout)?;
+                let counters = inventory.hit_counters()?;
+                if *json {
+                    println!("{}", serde_json::to_string_pretty(&counters)?);
+                } else {
+                    print!("{}", counters);
+                }
+                Ok(())
+            }
+            Subcommand::Stats { json, top, .. } => {
+                let _lock = inventory.lock(false, opt.lock_timeout)?;
+                let stats = inventory.stats(*top);
+                if *json {
+                    println!("{}", serde_json::to_string_pretty(&stats)?);
+                } else {
+                    print!("{}", stats);
+                }
+                Ok(())
+            }
+            Subcommand::List { sort, min_size } => {
+                let _lock = inventory.lock(false, opt.lock_timeout)?;
+                for (key, value) in inventory.list(*sort, min_size.unwrap_or(0)) {
+                    println!(
+                        "{:>16} {:016x}  {:>10}  {}",
+                        format!("{:x}", key.command_hash),
+                        key.input_hash,
+                        bytefmt::format(value.size),
+                        format_time(value.last_used)
+                    );
+                }
+                Ok(())
+            }
+            Subcommand::Clear { older_than } => {
+                let _lock = inventory.lock(true, opt.lock_timeout)?;
+                inventory.reload();
+                let (count, size) = inventory.clear(*older_than)?;
+                println!(
+                    "Removed {} entries, freeing {}",
+                    count,
+                    bytefmt::format(size)
+                );
+                Ok(())
+            }
+            Subcommand::Verify { repair } => {
+                let _lock = inventory.lock(*repair, opt.lock_timeout)?;
+                inventory.reload();
+                let report = inventory.verify(*repair)?;
+                print!("{}", report);
+                if !report.corrupt.is_empty() && !repair {
+                    return Err(eyre!(
+                        "The cache holds corrupt entries, pass --repair to remove them"
+                    ));
+                }
+                Ok(())
+            }
+        };
+    }
+
+    if opt.gc {
+        let _lock = inventory.lock(true, opt.lock_timeout)?;
+        inventory.reload();
+        let (count, size) = inventory.gc()?;
+        println!(
+            "Removed {} stray files, freeing {}",
+            count,
+            bytefmt::format(size)
+        );
+        if opt.command.is_empty() {
+            return Ok(());
+        }
+    }
+
+    for (output, input) in opt.overlapping_paths()? {
+        let message = format!(
+            "Output path {} overlaps input path {}, restoring it changes the key of the next run",
+            output.to_string_lossy(),
+            input.to_string_lossy()
+        );
+        if !opt.allow_overlap {
+            return Err(eyre!(
+                "{}. Exclude it from the key and pass --allow-overlap to run anyway",
+                message
+            ));
+        }
+        warn!("{}", message);
+    }
+
+    if let Some(max_age) = opt.max_age {
+        if opt.dry_run || opt.read_only {
+            for (key, _) in inventory.older_than(Some(max_age)) {
+                inventory.inv.remove(&key);
+            }
+        } else if !inventory.older_than(Some(max_age)).is_empty() {
+            let expired = inventory.lock(true, opt.lock_timeout).and_then(|_lock| {
+                inventory.reload();
+                inventory.clear(Some(max_age))
+            });
+            match expired {
+                Ok((count, size)) => info!(
+                    "Expired {} entries, freeing {}",
+                    count,
+                    bytefmt::format(size)
+                ),
+                Err(e) if opt.tolerates_cache_error(&e) => {
+                    warn!("Cannot expire cache entries, ignoring them: {:#}", e);
+                    for (key, _) in inventory.older_than(Some(max_age)) {
+                        inventory.inv.remove(&key);
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    let cur_key = compute_key(&opt).map_err(|e| warn!("{}", e)).ok();
+    trace!("Computed key: {:#?}", cur_key);
+    let guard = match &cur_key {
+        Some(_) if opt.collision_guard => Some(compute_collision_guard(&opt)?),
+        _ => None,
+    };
+
+    // Held until the process exits, so that other runs of the key find its entry once they get it
+    let _flight_lock = match &cur_key {
+        Some(key) if opt.single_flight && !opt.dry_run && !opt.read_only => {
+            match inventory.flight_lock(key, opt.lock_timeout) {
+                Ok(lock) => Some(lock),
+                Err(e) => {
+                    warn!("Not waiting for other runs of the same command: {:#}", e);
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+
+    let mut report = RunReport {
+        command_hash: cur_key
+            .as_ref()
+            .map(|key| format!("{:x}", key.command_hash)),
+        input_hash: cur_key
+            .as_ref()
+            .map(|key| format!("{:016x}", key.input_hash)),
+        ..RunReport::default()
+    };
+
+    if opt.force {
+        info!("Skipping cache lookup");
+    } else if let Some(cur_key) = &cur_key {
+        if let Some(Hit {
+            archive_size,
+            captured,
+        }) = try_restore(&mut inventory, &opt, cur_key, guard)?
+        {
+            report.hit = true;
+            report.archive_size = Some(archive_size);
+            if opt.dry_run {
+                println!(
+                    "Cache hit for {:x}/{:016x}",
+                    cur_key.command_hash, cur_key.input_hash
+                );
+            }
+            if let Some(captured) = captured {
+                captured.replay()?;
+                report.exit_code = Some(captured.exit_code);
+                if captured.exit_code != 0 {
+                    warn!(
+                        exit_code = captured.exit_code;
+                        "Command exited with code {} when cached, not running it again",
+                        captured.exit_code
+                    );
+                    report.save(opt.report.as_deref());
+                    std::process::exit(captured.exit_code);
+                }
+            }
+            report.save(opt.report.as_deref());
+            if !opt.dry_run {
+                log_cache_usage(&inventory, &opt);
+            }
+            if !opt.dry_run && opt.exit_code_hit != 0 {
+                std::process::exit(opt.exit_code_hit.into());
+            }
+            return Ok(());
+        }
+    }
+
+    if opt.dry_run {
+        match &cur_key {
+            Some(key) => println!(
+                "Cache miss for {:x}/{:016x}",
+                key.command_hash, key.input_hash
+            ),
+            None => println!("Cache miss, no key could be computed"),
+        }
+        report.save(opt.report.as_deref());
+        std::process::exit(2);
+    }
+
+    info!("Running command");
+    let mut command = if opt.shell {
+        let mut command = std::process::Command::new(SHELL[0]);
+        command.arg(SHELL[1]).arg(opt.command.join(" "));
+        command
+    } else {
+        let mut command = std::process::Command::new(&opt.command[0]);
+        command.args(&opt.command[1..]);
+        command
+    };
+    if opt.clear_env {
+        command.env_clear();
+    }
+    command.envs(opt.set_env_vars());
+    if opt.timeout.is_some() {
+        own_process_group(&mut command);
+    }
+    let mut attempt = 0;
+    let ran = loop {
+        let ran = if opt.capture {
+            CapturedOutput::run(&mut command, opt.timeout)
+                .map(|(exit_status, captured)| (exit_status, Some(captured)))
+        } else {
+            command
+                .spawn()
+                .map_err(|e| start_error(&command, e))
+                .and_then(|mut child| wait_for(&mut child, opt.timeout))
+                .map(|exit_status| (exit_status, None))
+        };
+        // Only the last attempt is cached, whatever its outcome
+        match &ran {
+            Ok((exit_status, _)) if !exit_status.success() && attempt < opt.retries => {
+                attempt += 1;
+                warn!(
+                    exit_code = exit_code(*exit_status);
+                    "Command exited with code {}, retrying in {} ({}/{})",
+                    exit_code(*exit_status),
+                    humantime::format_duration(opt.retry_delay),
+                    attempt,
+                    opt.retries
+                );
+                std::thread::sleep(opt.retry_delay);
+            }
+            _ => break ran,
+        }
+    };
+    let (exit_status, captured) = match ran {
+        Err(e) if e.downcast_ref::<CommandNotFound>().is_some() => {
+            warn!(exit_code = COMMAND_NOT_FOUND_EXIT_CODE; "{}", e);
+            report.exit_code = Some(COMMAND_NOT_FOUND_EXIT_CODE);
+            report.save(opt.report.as_deref());
+            std::process::exit(COMMAND_NOT_FOUND_EXIT_CODE)
+        }
+        Err(e) if e.downcast_ref::<CommandTimedOut>().is_some() => {
+            warn!(exit_code = TIMEOUT_EXIT_CODE; "{}, nothing was cached", e);
+            report.exit_code = Some(TIMEOUT_EXIT_CODE);
+            report.save(opt.report.as_deref());
+            std::process::exit(TIMEOUT_EXIT_CODE)
+        }
+        ran => ran?,
+    };
+    report.exit_code = Some(exit_code(exit_status));
+    if !exit_status.success() {
+        match exit_signal(exit_status) {
+            Some(signal) => warn!(
+                exit_code = exit_code(exit_status);
+                "Command was killed by signal {}, nothing was cached",
+                signal
+            ),
+            None if opt.cache_failures => {
+                warn!(
+                    exit_code = exit_code(exit_status);
+                    "Command exited with code {}, caching the failure",
+                    exit_code(exit_status)
+                );
+                let failure = captured.unwrap_or_else(|| CapturedOutput {
+                    exit_code: exit_code(exit_status),
+                    ..CapturedOutput::default()
+                });
+                if let Some(cur_key) = &cur_key {
+                    report.archive_size =
+                        cache_run(&mut inventory, &opt, cur_key, Some(&failure), guard)?;
+                }
+            }
+            None => warn!(
+                exit_code = exit_code(exit_status);
+                "Command exited with code {}, nothing was cached",
+                exit_code(exit_status)
+            ),
+        }
+        report.save(opt.report.as_deref());
+        std::process::exit(exit_code(exit_status))
+    }
+    trace!("Command was successful");
+
+    if let Some(cur_key) = &cur_key {
+        report.archive_size = cache_run(&mut inventory, &opt, cur_key, captured.as_ref(), guard)?;
+    }
+
+    report.save(opt.report.as_deref());
+    log_cache_usage(&inventory, &opt);
+    if opt.exit_code_miss != 0 {
+        std::process::exit(opt.exit_code_miss.into());
+    }
+    Ok(())
+}
+
+/// Logs how much of `--max-cache-size` the cache takes up, to notice when it nears it
+fn log_cache_usage(inventory: &Inventory, config: &Config) {
+    info!(
+        "Cache at {} of {}",
+        bytefmt::format(inventory.disk_size()),
+        bytefmt::format(config.max_cache_size)
+    );
+}
+
+/// Stores a run unless the cache is read-only, returning the size of the archive written. Failing
+/// to write to the cache only gets a warning, unless `--strict-cache` is given.
+fn cache_run(
+    inventory: &mut Inventory,
+    config: &Config,
+    key: &CommandInputHashes,
+    captured: Option<&CapturedOutput>,
+    guard: Option<blake3::Hash>,
+) -> Result<Option<u64>> {
+    if config.read_only {
+        return Ok(None);
+    }
+    match store(inventory, config, key, captured, guard) {
+        Ok(size) => Ok(Some(size)),
+        Err(e) if config.tolerates_cache_error(&e) => {
+            warn!("Cannot write to the cache, nothing was cached: {:#}", e);
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Computes the key a run is cached under, from its command line and the contents of its inputs
+pub fn compute_key(config: &Config) -> Result<CommandInputHashes> {
+    config.command_input_key()
+}
+
+/// Computes the digest `--collision-guard` stores with entries: the same walk as for the key,
+/// hashed with full blake3 digests throughout
+pub fn compute_collision_guard(config: &Config) -> Result<blake3::Hash> {
+    config.collision_guard()
+}
+
+/// Outcome of a successful cache lookup
+#[derive(Debug)]
+pub struct Hit {
+    /// Size of the archive the outputs were restored from
+    pub archive_size: u64,
+    /// Output of the command, when running with `--capture`
+    pub captured: Option<CapturedOutput>,
+}
+
+/// Restores the outputs cached under `key`, returning `None` on a miss. With `--dry-run`,
+/// nothing is restored but hits are still reported.
+pub fn try_restore(
+    inventory: &mut Inventory,
+    config: &Config,
+    key: &CommandInputHashes,
+    guard: Option<blake3::Hash>,
+) -> Result<Option<Hit>> {
+    let _lock = inventory.lock(false, config.lock_timeout)?;
+    let restored = inventory.try_restore_from_cache(
+        key,
+        &config.output_paths(),
+        RestoreOptions {
+            capture: config.capture,
+            touch: config.touch_output,
+            cache_failures: config.cache_failures,
+            verify: config.verify_restore,
+            max_size: config.max_restore_size,
+            guard,
+            dry_run: config.dry_run,
+        },
+    );
+    let hit = restored.map(|captured| Hit {
+        archive_size: inventory.inv.get(key).map_or(0, |value| value.size),
+        captured,
+    });
+    if !config.dry_run {
+        inventory
+            .update_hit_counters(|counters| match &hit {
+                Some(hit) => {
+                    counters.hits += 1;
+                    counters.restored_bytes += hit.archive_size;
+                }
+                None => counters.misses += 1,
+            })
+            .unwrap_or_else(|e| warn!("Cannot count cache hits: {}", e));
+    }
+    Ok(hit)
+}
+
+/// Caches the outputs of a successful run under `key`, along with its output if captured, or only
+/// the exit code and output of a failed one, then evicts entries until the cache fits its limits.
+/// Returns the size of the archive written.
+pub fn store(
+    inventory: &mut Inventory,
+    config: &Config,
+    key: &CommandInputHashes,
+    captured: Option<&CapturedOutput>,
+    guard: Option<blake3::Hash>,
+) -> Result<u64> {
+    let _lock = inventory.lock(true, config.lock_timeout)?;
+    // Another process may have changed the cache since it was loaded
+    inventory.reload();
+    let failed = captured.is_some_and(|captured| captured.exit_code != 0);
+    let output_paths = if failed {
+        Vec::new()
+    } else {
+        config.output_paths()
+    };
+    // The archive size is only known once written, so limits are enforced afterwards
+    let size = inventory.write_to_cache(
+        &output_paths,
+        key,
+        captured,
+        config.compression_jobs(),
+        config.archive_times(),
+        &config.progress_bar("{spinner} Writing to cache: {bytes} ({binary_bytes_per_sec})"),
+    )?;
+    if let Some(guard) = guard {
+        inventory.record_guard(key, guard)?;
+    }
+    if config.hash_cache && config.key.is_none() && !failed {
+        FileHashCache::record_run(config, key.command_hash)
+            .unwrap_or_else(|e| warn!("Cannot save file hashes: {}", e));
+    }
+    inventory.discard_until(key, config.max_cache_size, config.max_entries)?;
+    Ok(size)
+}
+
+/// Environment variable setting the cache path when `--cache-path` is not given
+const CACHE_PATH_VAR: &str = "FOLCA_CACHE";
+
+const MISSING_REQUIRED: &str =
+    "<input-path>, <output-path> and <command> are required without a subcommand";
+
+const PRINT_KEY_WITHOUT_RUN: &str =
+    "--print-key needs <input-path>, <output-path> and <command>, and no subcommand";
+
+const HASHING_PROGRESS: &str = "{spinner} Hashing inputs: {human_pos} files";
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "folca",
+    about = "Folder-based command cache",
+    usage = "folca [FLAGS] [OPTIONS] <input-path> <output-path> <command>...
+    folca [FLAGS] [OPTIONS] <SUBCOMMAND>"
+)]
+/// Options of a run, as given on the command line and in `.folca.toml`. Outside of the command
+/// line tool, build it with [`Config::parse_args`].
+pub struct Config {
+    /// Respect `.ignore` and `.gitignore` files
+    #[structopt(long)]
+    respect_ignore: bool,
+
+    /// Hash hidden files
+    #[structopt(long)]
+    include_hidden: bool,
+
+    #[structopt(
+        long,
+        parse(from_os_str),
+        default_value = ".folca_cache",
+        env = CACHE_PATH_VAR
+    )]
+    cache_path: PathBuf,
+
+    /// Spread the cache over directories named after the first byte of the command hashes, for
+    /// filesystems that are slow with many entries in one directory
+    #[structopt(long)]
+    shard: bool,
+
+    /// Also spread the entries of each command over directories named after the first two digits
+    /// of their input hash. Entries written with other sharding options are moved when found
+    #[structopt(long)]
+    shard_inputs: bool,
+
+    /// Base URL of a shared HTTP cache, local misses are fetched from it and new entries uploaded
+    #[structopt(long)]
+    remote_url: Option<String>,
+
+    /// S3 bucket to use as remote cache, credentials are read from the usual AWS variables
+    #[structopt(long, conflicts_with = "remote-url")]
+    s3_bucket: Option<String>,
+
+    /// Prefix of the cache entries in the S3 bucket
+    #[structopt(long, default_value = "")]
+    s3_prefix: String,
+
+    #[structopt(long, default_value = "10 GB", parse(try_from_str = Self::non_zero_bytes))]
+    max_cache_size: u64,
+
+    /// Maximum number of entries to keep in the cache
+    #[structopt(long, parse(try_from_str = Self::non_zero_count))]
+    max_entries: Option<usize>,
+
+    /// Drop entries last used longer ago than this, e.g. `7d` or `12h`
+    #[structopt(long, parse(try_from_str = Self::non_zero_duration))]
+    max_age: Option<Duration>,
+
+    /// Kill the command if it runs longer than this, e.g. `30m`, and exit with 124 without
+    /// caching anything
+    #[structopt(long, parse(try_from_str = Self::non_zero_duration))]
+    timeout: Option<Duration>,
+
+    /// Run the command again up to this many times when it fails, caching the first success.
+    /// Runs killed by `--timeout` are not retried
+    #[structopt(long, default_value = "0")]
+    retries: usize,
+
+    /// How long to wait before running a failed command again
+    #[structopt(long, default_value = "1s", parse(try_from_str = Self::parse_duration))]
+    retry_delay: Duration,
+
+    /// How long to wait for other folca processes to release the cache
+    #[structopt(long, default_value = "10m", parse(try_from_str = Self::non_zero_duration))]
+    lock_timeout: Duration,
+
+    /// Let concurrent runs with the same key wait for the first one and restore what it cached,
+    /// rather than all running the command. They run it anyway after `--lock-timeout`
+    #[structopt(long)]
+    single_flight: bool,
+
+    /// Hashing algorithm used to compute the cache key
+    #[structopt(long, default_value = "fnv1a", possible_values = HashAlgo::VARIANTS)]
+    hash_algo: HashAlgo,
+
+    /// Hash the command and inputs a second time into a full 256-bit blake3 digest, stored with
+    /// each entry, so that a key shared by different inputs is a miss rather than wrong outputs
+    #[structopt(long)]
+    collision_guard: bool,
+
+    /// How paths are hashed: `portable` uses `/` as separator on every platform, `lowercase` also
+    /// ignores case and `exact` keeps the platform's own bytes
+    #[structopt(long, default_value = "portable", possible_values = PathNormalization::VARIANTS)]
+    path_normalization: PathNormalization,
+
+    /// Compression used for new cache entries, existing ones are read using their own
+    #[structopt(long, default_value = "gzip", possible_values = CompressionAlgo::VARIANTS)]
+    compression: CompressionAlgo,
+
+    /// Compression level from 0 (store only) to 9 (best), defaults to the codec's own default
+    #[structopt(long, parse(try_from_str = Self::compression_level))]
+    compression_level: Option<u32>,
+
+    /// Store entries uncompressed when a sample of the outputs deflates to more than this
+    /// fraction of its size, e.g. 0.9 for outputs that are already compressed
+    #[structopt(long, parse(try_from_str = Self::compress_threshold))]
+    compress_threshold: Option<f64>,
+
+    /// zstd dictionary to compress new entries with, e.g. trained with `zstd --train` on typical
+    /// outputs. Entries written with another dictionary are misses
+    #[structopt(long, parse(from_os_str))]
+    dict: Option<PathBuf>,
+
+    /// Number of compression threads, defaults to the number of CPUs
+    #[structopt(long, parse(try_from_str = Self::non_zero_count))]
+    jobs: Option<usize>,
+
+    /// Write archives that only depend on the content of the outputs, with timestamps and
+    /// ownership zeroed. Restored files get the current time
+    #[structopt(long)]
+    reproducible: bool,
+
+    /// Record the modification times of outputs to the nanosecond and restore them exactly,
+    /// rather than rounded down to the second, for tools such as `make` that compare them
+    #[structopt(long, conflicts_with = "reproducible")]
+    preserve_mtime: bool,
+
+    /// Set the modification times of restored outputs to the current time, for build systems
+    /// that would otherwise consider them stale
+    #[structopt(long, conflicts_with = "preserve-mtime")]
+    touch_output: bool,
+
+    /// Hash restored outputs and run the command if they differ from what was cached, which
+    /// catches filesystem corruption and faulty unpacks
+    #[structopt(long)]
+    verify_restore: bool,
+
+    /// Refuse to restore entries that decompress to more than this, guarding shared caches
+    /// against archives crafted to expand without bound. The command then runs instead
+    #[structopt(long, parse(try_from_str = Self::non_zero_bytes))]
+    max_restore_size: Option<u64>,
+
+    /// Verbose
+    #[structopt(short, long, parse(from_occurrences))]
+    verbose: u8,
+
+    /// Format of log lines, `json` prints one object per line on stderr
+    #[structopt(long, default_value = "text", possible_values = LogFormat::VARIANTS)]
+    log_format: LogFormat,
+
+    /// Prefix every text log line with the local time in this strftime format, such as
+    /// `%Y-%m-%d %H:%M:%S`. By default only debug lines get minutes and seconds
+    #[structopt(long, parse(try_from_str = Self::time_format))]
+    log_time_format: Option<String>,
+
+    /// When to color text log lines
+    #[structopt(long, default_value = "auto", possible_values = ColorWhen::VARIANTS)]
+    color: ColorWhen,
+
+    /// Write a JSON summary of the run to this file: whether it was a cache hit, the key and the
+    /// archive size
+    #[structopt(long, parse(from_os_str))]
+    report: Option<PathBuf>,
+
+    /// Exit with this code when the outputs are restored from the cache, so that scripts can tell
+    #[structopt(long, default_value = "0")]
+    exit_code_hit: u8,
+
+    /// Exit with this code when the command ran successfully on a cache miss
+    #[structopt(long, default_value = "0")]
+    exit_code_miss: u8,
+
+    /// Do not show progress bars, which are only shown when stdout is a terminal
+    #[structopt(short, long)]
+    quiet: bool,
+
+    #[structopt(parse(from_os_str))]
+    input_path: Option<PathBuf>,
+
+    /// Additional input path to hash, may be repeated
+    #[structopt(long, parse(from_os_str), number_of_values = 1)]
+    input: Vec<PathBuf>,
+
+    #[structopt(parse(from_os_str))]
+    output_path: Option<PathBuf>,
+
+    /// Additional output path to cache, may be repeated
+    #[structopt(long, parse(from_os_str), number_of_values = 1)]
+    output: Vec<PathBuf>,
+
+    /// Gitignore-style glob of input files to leave out of the key, may be repeated
+    #[structopt(long, number_of_values = 1)]
+    exclude: Vec<String>,
+
+    /// Only hash input files matching this glob, relative to their input path, may be repeated.
+    /// Excludes and `.folcaignore` files take precedence
+    #[structopt(long, number_of_values = 1)]
+    include: Vec<String>,
+
+    /// Hash the contents symlinks point to instead of their target path
+    #[structopt(long)]
+    follow_symlinks: bool,
+
+    /// Leave sockets, fifos and devices out of the key. By default only their path is hashed,
+    /// they are never read
+    #[structopt(long)]
+    skip_special: bool,
+
+    /// Also hash the permission bits of input files, as they are restored along with the outputs
+    #[structopt(long)]
+    hash_mode: bool,
+
+    /// Use this string instead of the contents of the input paths as key, combined with the command
+    #[structopt(long)]
+    key: Option<String>,
+
+    /// Leave the command line out of the key, so that runs on the same inputs share an entry
+    /// whatever their command. Dangerous: a different command gets the outputs of another
+    #[structopt(long)]
+    ignore_command: bool,
+
+    /// Environment variable the command depends on, whose value is hashed along with the command,
+    /// may be repeated. An unset variable hashes differently from an empty one
+    #[structopt(long, number_of_values = 1)]
+    env_key: Vec<String>,
+
+    /// Set an environment variable for the command, as `NAME=VALUE`, may be repeated. The
+    /// variables are hashed along with the command
+    #[structopt(long, number_of_values = 1, parse(try_from_str = Self::env_assignment))]
+    set_env: Vec<(String, String)>,
+
+    /// Run the command with an empty environment, apart from `--set-env` variables
+    #[structopt(long)]
+    clear_env: bool,
+
+    /// Hash the working directory along with the command, for commands whose result depends on
+    /// where they run. Entries then only match in the same directory, on this machine or another
+    #[structopt(long)]
+    hash_cwd: bool,
+
+    /// Fold this string into the key, so that projects sharing a cache never share entries
+    #[structopt(long)]
+    namespace: Option<String>,
+
+    /// Run even when an output path is inside an input path or the other way around, e.g. when
+    /// the output is excluded from the key. Otherwise restoring it would change the next key
+    #[structopt(long)]
+    allow_overlap: bool,
+
+    /// Cache even when the input paths are missing or hold no files, which otherwise is
+    /// refused as all such runs would share a key
+    #[structopt(long)]
+    allow_empty_input: bool,
+
+    /// Only hash the size and the first and last this many bytes of larger input files. Faster on
+    /// huge files, but files differing elsewhere collide and then share a cache entry
+    #[structopt(long, parse(try_from_str = Self::non_zero_bytes))]
+    sample_bytes: Option<u64>,
+
+    /// Only hash the size and modification time of input files larger than this, not their
+    /// content. Much faster on huge artifacts, but a change that keeps both goes unnoticed
+    #[structopt(long, parse(try_from_str = Self::non_zero_bytes))]
+    max_file_size: Option<u64>,
+
+    /// Size of the reads input files are hashed with, one buffer is used per hashing thread
+    #[structopt(long, default_value = "1 MiB", parse(try_from_str = Self::non_zero_bytes))]
+    read_buffer_size: u64,
+
+    /// Remember file hashes in the cache directory, keyed on path, modification time and size,
+    /// so unchanged files are not read again
+    #[structopt(long)]
+    hash_cache: bool,
+
+    /// Also cache the command's stdout, stderr and exit code, and replay them on a cache hit
+    #[structopt(long)]
+    capture: bool,
+
+    /// Also cache runs that exit with a non-zero code, and replay them on a cache hit instead of
+    /// running the command again. Runs killed by a signal are never cached
+    #[structopt(long)]
+    cache_failures: bool,
+
+    command: Vec<String>,
+
+    /// Run the command through the shell, `sh -c` or `cmd /C` on Windows, with its arguments
+    /// joined by spaces, so that pipes and builtins work
+    #[structopt(long)]
+    shell: bool,
+
+    /// Read the command from this file instead, one argument per line, or from stdin with `-`
+    #[structopt(long, parse(from_os_str))]
+    command_file: Option<PathBuf>,
+
+    /// Run the command even on a cache hit, replacing the cached entry with its result
+    #[structopt(long, visible_alias = "no-read")]
+    force: bool,
+
+    /// Restore from the cache but never write to it, e.g. when it is mounted read-only
+    #[structopt(long)]
+    read_only: bool,
+
+    /// Fail when the cache cannot be written to, instead of warning and exiting with the
+    /// command's result as if it had not been cached
+    #[structopt(long)]
+    strict_cache: bool,
+
+    /// Do not run command or modify cache, exit with 0 if the entry is cached and 2 otherwise
+    /// + log intermediate hashes (has a performance hit)
+    /// + log input files changed since the last cached run of the command, with `--hash-cache`
+    #[structopt(long)]
+    dry_run: bool,
+
+    /// Only print the key the run would be cached under, as `COMMAND_HASH/INPUT_HASH`, without
+    /// running the command or touching the cache
+    #[structopt(long, conflicts_with = "gc")]
+    print_key: bool,
+
+    /// Delete unfinished writes, orphaned sidecars and unrecognized files from the cache, then
+    /// run the command if one is given
+    #[structopt(long)]
+    gc: bool,
+
+    #[structopt(subcommand)]
+    subcommand: Option<Subcommand>,
+
+    #[structopt(skip)]
+    config_file: Option<PathBuf>,
+}
+
+/// Options read from the nearest `.folca.toml` in the current directory or its parents.
+/// Keys are named after the command line options, e.g. `cache_path` for `--cache-path`,
+/// and only apply when the option is not given on the command line.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    respect_ignore: Option<bool>,
+    include_hidden: Option<bool>,
+    cache_path: Option<PathBuf>,
+    shard: Option<bool>,
+    shard_inputs: Option<bool>,
+    remote_url: Option<String>,
+    s3_bucket: Option<String>,
+    s3_prefix: Option<String>,
+    max_cache_size: Option<String>,
+    max_entries: Option<usize>,
+    max_age: Option<String>,
+    timeout: Option<String>,
+    retries: Option<usize>,
+    retry_delay: Option<String>,
+    lock_timeout: Option<String>,
+    single_flight: Option<bool>,
+    hash_algo: Option<String>,
+    collision_guard: Option<bool>,
+    path_normalization: Option<String>,
+    compression: Option<String>,
+    compression_level: Option<u32>,
+    compress_threshold: Option<f64>,
+    dict: Option<String>,
+    jobs: Option<usize>,
+    reproducible: Option<bool>,
+    preserve_mtime: Option<bool>,
+    touch_output: Option<bool>,
+    verify_restore: Option<bool>,
+    max_restore_size: Option<String>,
+    verbose: Option<u8>,
+    quiet: Option<bool>,
+    log_format: Option<String>,
+    log_time_format: Option<String>,
+    color: Option<String>,
+    exclude: Option<Vec<String>>,
+    include: Option<Vec<String>>,
+    env_key: Option<Vec<String>>,
+    set_env: Option<Vec<String>>,
+    clear_env: Option<bool>,
+    hash_cwd: Option<bool>,
+    namespace: Option<String>,
+    allow_overlap: Option<bool>,
+    allow_empty_input: Option<bool>,
+    sample_bytes: Option<String>,
+    max_file_size: Option<String>,
+    read_buffer_size: Option<String>,
+    hash_cache: Option<bool>,
+    hash_mode: Option<bool>,
+    follow_symlinks: Option<bool>,
+    skip_special: Option<bool>,
+    capture: Option<bool>,
+    cache_failures: Option<bool>,
+    read_only: Option<bool>,
+    strict_cache: Option<bool>,
+}
+
+impl FileConfig {
+    const FILE_NAME: &'static str = ".folca.toml";
+
+    fn discover() -> Result<Option<PathBuf>> {
+        Ok(std::env::current_dir()?
+            .ancestors()
+            .map(|dir| dir.join(Self::FILE_NAME))
+            .find(|path| path.is_file()))
+    }
+
+    fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .wrap_err(format!("Cannot read {}", path.to_string_lossy()))?;
+        toml::from_str(&contents).wrap_err(format!("Cannot parse {}", path.to_string_lossy()))
+    }
+
+    fn merge_into(self, opt: &mut Config, matches: &ArgMatches, path: &Path) -> Result<()> {
+        // Arguments are named after their long option, not after the field
+        let unset = |field: &str| matches.occurrences_of(field.replace('_', "-")) == 0;
+        macro_rules! merge {
+            ($field:ident) => {
+                if let Some(value) = self.$field {
+                    if unset(stringify!($field)) {
+                        opt.$field = value;
+                    }
+                }
+            };
+            ($field:ident, $parse:expr) => {
+                if let Some(value) = self.$field {
+                    if unset(stringify!($field)) {
+                        opt.$field = ($parse)(&value.to_string()).map_err(|e| {
+                            eyre!(
+                                "Invalid {} in {}: {}",
+                                stringify!($field),
+                                path.to_string_lossy(),
+                                e
+                            )
+                        })?;
+                    }
+                }
+            };
+        }
+
+        merge!(respect_ignore);
+        merge!(include_hidden);
+        // The environment is more specific to a run than a file shared by a whole tree
+        if std::env::var_os(CACHE_PATH_VAR).is_none() {
+            merge!(cache_path);
+        }
+        merge!(shard);
+        merge!(shard_inputs);
+        merge!(remote_url, |v: &str| Ok::<_, String>(Some(v.to_string())));
+        merge!(s3_bucket, |v: &str| Ok::<_, String>(Some(v.to_string())));
+        merge!(s3_prefix);
+        merge!(max_cache_size, Config::non_zero_bytes);
+        merge!(max_entries, |v: &str| Config::non_zero_count(v).map(Some));
+        merge!(max_age, |v: &str| Config::non_zero_duration(v).map(Some));
+        merge!(timeout, |v: &str| Config::non_zero_duration(v).map(Some));
+        merge!(retries);
+        merge!(retry_delay, Config::parse_duration);
+        merge!(lock_timeout, Config::non_zero_duration);
+        merge!(single_flight);
+        merge!(hash_algo, HashAlgo::from_str);
+        merge!(collision_guard);
+        merge!(path_normalization, PathNormalization::from_str);
+        merge!(compression, CompressionAlgo::from_str);
+        merge!(compression_level, |v: &str| Config::compression_level(v)
+            .map(Some));
+        merge!(compress_threshold, |v: &str| Config::compress_threshold(v)
+            .map(Some));
+        merge!(dict, |v: &str| Ok::<_, String>(Some(PathBuf::from(v))));
+        merge!(jobs, |v: &str| Config::non_zero_count(v).map(Some));
+        merge!(reproducible);
+        merge!(preserve_mtime);
+        merge!(touch_output);
+        merge!(verify_restore);
+        merge!(max_restore_size, |v: &str| Config::non_zero_bytes(v)
+            .map(Some));
+        merge!(verbose);
+        merge!(quiet);
+        merge!(log_format, LogFormat::from_str);
+        merge!(log_time_format, |v: &str| Config::time_format(v).map(Some));
+        merge!(color, ColorWhen::from_str);
+        merge!(exclude);
+        merge!(include);
+        merge!(env_key);
+        if let Some(assignments) = &self.set_env {
+            if unset("set_env") {
+                opt.set_env = assignments
+                    .iter()
+                    .map(|assignment| Config::env_assignment(assignment))
+                    .collect::<Result<_, _>>()
+                    .map_err(|e| eyre!("Invalid set_env in {}: {}", path.to_string_lossy(), e))?;
+            }
+        }
+        merge!(clear_env);
+        merge!(hash_cwd);
+        merge!(namespace, |v: &str| Ok::<_, String>(Some(v.to_string())));
+        merge!(allow_overlap);
+        merge!(allow_empty_input);
+        merge!(sample_bytes, |v: &str| Config::non_zero_bytes(v).map(Some));
+        merge!(max_file_size, |v: &str| Config::non_zero_bytes(v).map(Some));
+        merge!(read_buffer_size, Config::non_zero_bytes);
+        merge!(hash_cache);
+        merge!(hash_mode);
+        merge!(follow_symlinks);
+        merge!(skip_special);
+        merge!(capture);
+        merge!(cache_failures);
+        merge!(read_only);
+        merge!(strict_cache);
+        Ok(())
+    }
+}
+
+#[derive(Debug, StructOpt)]
+enum Subcommand {
+    /// Print a summary of the cache contents
+    Stats {
+        /// Print as JSON
+        #[structopt(long)]
+        json: bool,
+
+        /// Number of largest entries to list
+        #[structopt(long, default_value = "10")]
+        top: usize,
+
+        /// Print the number of hits and misses since the cache was created instead
+        #[structopt(long)]
+        hits: bool,
+    },
+    /// List the cache entries with their key, size and last use
+    List {
+        /// `size` lists the largest entries first, `age` the least recently used ones
+        #[structopt(long, default_value = "size", possible_values = ListSort::VARIANTS)]
+        sort: ListSort,
+
+        /// Leave out entries smaller than this, e.g. `100 MB`
+        #[structopt(long, parse(try_from_str = bytefmt::parse))]
+        min_size: Option<u64>,
+    },
+    /// Remove cache entries
+    Clear {
+        /// Only remove entries last used longer ago than this, e.g. `7d` or `12h`
+        #[structopt(long, parse(try_from_str = Config::non_zero_duration))]
+        older_than: Option<Duration>,
+    },
+    /// Check that every entry has its checksum and decompresses to a valid archive, failing if
+    /// any does not
+    Verify {
+        /// Remove the corrupt entries instead of failing
+        #[structopt(long)]
+        repair: bool,
+    },
+}
+
+/// The local cache and its entries, along with the remote cache if any
+#[derive(Debug)]
+pub struct Inventory {
+    inv: HashMap<CommandInputHashes, LastUsedAndSize>,
+    cache_path: PathBuf,
+    layout: CacheLayout,
+    /// Every layout along with the regex matching its entry names
+    layouts: Vec<(CacheLayout, Regex)>,
+    hash_algo: HashAlgo,
+    compression: CompressionAlgo,
+    compression_level: Option<u32>,
+    /// See `--compress-threshold`
+    compress_threshold: Option<f64>,
+    read_only: bool,
+    local: FilesystemBackend,
+    remote: Option<Box<dyn CacheBackend>>,
+    /// Time entries are stamped with when written or used, and aged against
+    clock: Clock,
+    /// See `--dict`
+    dictionary: Option<ZstdDictionary>,
+}
+
+impl Inventory {
+    /// Loads the cache a run is configured to use
+    pub fn open(config: &Config) -> Result<Self> {
+        let mut inventory = Self::load(
+            config.cache_path.clone(),
+            CacheLayout {
+                shard: config.shard,
+                shard_inputs: config.shard_inputs,
+            },
+            config.hash_algo,
+            config.compression,
+            config.compression_level,
+            config.read_only,
+            config.remote_backend()?,
+        );
+        inventory.compress_threshold = config.compress_threshold;
+        inventory.dictionary = config
+            .dict
+            .as_deref()
+            .map(ZstdDictionary::load)
+            .transpose()?;
+        Ok(inventory)
+    }
+
+    /// Uses `clock` rather than the system time for the last use of entries and their age, so
+    /// that eviction can be replayed deterministically
+    pub fn with_clock(mut self, clock: impl Fn() -> SystemTime + Send + Sync + 'static) -> Self {
+        self.clock = Clock(Box::new(clock));
+        self
+    }
+
+    /// Parses the key and compression of an entry from its archive's path, along with the layout
+    /// it was written with. The raw bytes of the path are matched, so that a cache path which is
+    /// not valid UTF-8 is no obstacle.
+    fn parse_entry_path(
+        &self,
+        path: &Path,
+    ) -> Result<(CacheLayout, CommandInputHashes, CompressionAlgo)> {
+        let relative = path
+            .strip_prefix(&self.cache_path)
+            .map_err(|_| eyre!("Not in the cache: {}", path.to_string_lossy()))?;
+        let path_bytes = portable_path_bytes(relative);
+        self.layouts
+            .iter()
+            .find_map(|(layout, regex)| {
+                Self::parse_entry_name(*layout, regex, &path_bytes)
+                    .map(|(key, compression)| (*layout, key, compression))
+            })
+            .ok_or_else(|| eyre!("Not a cache entry: {}", path.to_string_lossy()))
+    }
+
+    fn parse_entry_name(
+        layout: CacheLayout,
+        regex: &Regex,
+        name: &[u8],
+    ) -> Option<(CommandInputHashes, CompressionAlgo)> {
+        let caps = regex.captures(name)?;
+        let group = |index: usize| std::str::from_utf8(&caps[index]).ok();
+
+        let key = CommandInputHashes {
+            command_hash: u64::from_str_radix(group(1)?, 16).ok()?,
+            input_hash: u64::from_str_radix(group(2)?, 16).ok()?,
+        };
+        let compression = CompressionAlgo::from_extension(group(3)?)?;
+        // The regex does not tell whether shard directories belong to the hashes
+        if layout.entry_name(&key, compression.extension()).as_bytes() != name {
+            return None;
+        }
+        Some((key, compression))
+    }
+
+    /// Adds the entry at `path` to the inventory, moving it first if it was written with another
+    /// layout
+    fn load_entry(&mut self, path: PathBuf) -> Result<()> {
+        let (layout, key, compression) = self.parse_entry_path(&path)?;
+        // Sidecars predate the index, whatever they recorded is carried over when rebuilding it
+        let sidecar = self
+            .read_metadata(
+                &self
+                    .cache_path
+                    .join(layout.entry_name(&key, METADATA_EXTENSION)),
+            )
+            .unwrap_or_default();
+        let path = if layout == self.layout {
+            path
+        } else if self.read_only {
+            return Err(eyre!(
+                "{} was written with other sharding options",
+                path.to_string_lossy()
+            ));
+        } else {
+            let moved = self.to_path(&key, compression);
+            self.relocate(&path, &moved)?;
+            moved
+        };
+        let metadata = path.metadata()?;
+        let last_used = match sidecar.last_used {
+            Some(last_used) => last_used,
+            None => metadata.modified()?,
+        };
+        let checksum = sidecar
+            .checksum
+            .and_then(|checksum| blake3::Hash::from_hex(checksum).ok());
+        let dictionary = ZstdDictionary::recorded_in(&path, compression)?;
+
+        self.inv.insert(
+            key,
+            LastUsedAndSize {
+                last_used,
+                size: metadata.len(),
+                compression,
+                checksum,
+                output_digest: None,
+                failed: false,
+                dictionary,
+                guard: None,
+            },
+        );
+
+        Ok(())
+    }
+
+    fn load(
+        path: PathBuf,
+        layout: CacheLayout,
+        hash_algo: HashAlgo,
+        compression: CompressionAlgo,
+        compression_level: Option<u32>,
+        read_only: bool,
+        remote: Option<Box<dyn CacheBackend>>,
+    ) -> Self {
+        let mut result = Self {
+            inv: HashMap::new(),
+            local: FilesystemBackend { root: path.clone() },
+            remote,
+            cache_path: path,
+            layout,
+            layouts: CacheLayout::ALL
+                .iter()
+                .map(|layout| (*layout, layout.regex()))
+                .collect(),
+            hash_algo,
+            compression: match compression_level {
+                Some(0) => CompressionAlgo::None,
+                _ => compression,
+            },
+            compression_level,
+            compress_threshold: None,
+            read_only,
+            clock: Clock(Box::new(SystemTime::now)),
+            dictionary: None,
+        };
+
+        if !result.cache_path.exists() {
+            info!("Cache path does not exist");
+            return result;
+        }
+
+        match std::fs::read_to_string(result.hash_algo_path()) {
+            Ok(cached_algo) if cached_algo.trim() != hash_algo.to_string() => warn!(
+                "Cache was populated using {} but {} is in use, existing entries will not match",
+                cached_algo.trim(),
+                hash_algo
+            ),
+            Ok(_) => {}
+            Err(e) => trace!("Cannot read hash algorithm marker: {}", e),
+        }
+
+        result.scan();
+        result
+    }
+
+    fn reload(&mut self) {
+        trace!("Reloading cache entries");
+        self.inv.clear();
+        if self.cache_path.exists() {
+            self.scan();
+        }
+    }
+
+    /// Loads the entries from the index, falling back to walking the cache directory when there
+    /// is no usable index. Entries whose archive has gone missing are dropped from the index.
+    fn scan(&mut self) {
+        match self.read_index() {
+            Ok(Some(inv)) => self.inv = inv,
+            Ok(None) => {
+                trace!("No cache index, rebuilding it from the cache directory");
+                return self.rebuild_index();
+            }
+            Err(e) => {
+                warn!(
+                    "Cannot read cache index, rebuilding it from the cache directory: {}",
+                    e
+                );
+                return self.rebuild_index();
+            }
+        }
+
+        let missing: Vec<CommandInputHashes> = self
+            .inv
+            .iter()
+            .filter(|(key, value)| !self.to_path(key, value.compression).exists())
+            .map(|(key, _)| key.clone())
+            .filter(|key| !self.move_from_other_layout(key))
+            .collect();
+        if missing.is_empty() {
+            return;
+        }
+        for key in &missing {
+            info!(
+                "Dropping {:x}/{:016x} from the cache index, its archive is missing",
+                key.command_hash, key.input_hash
+            );
+            self.inv.remove(key);
+        }
+        self.update_index(|index| {
+            for key in &missing {
+                index.remove(key);
+            }
+        })
+        .unwrap_or_else(|e| warn!("Cannot update cache index: {}", e));
+    }
+
+    /// Moves the archive of an indexed entry into place when it was written with other sharding
+    /// options, returning whether it was found
+    fn move_from_other_layout(&self, key: &CommandInputHashes) -> bool {
+        let compression = match self.inv.get(key) {
+            Some(value) => value.compression,
+            None => return false,
+        };
+        if self.read_only {
+            return false;
+        }
+        let target = self.to_path(key, compression);
+        let found = CacheLayout::ALL
+            .iter()
+            .filter(|layout| **layout != self.layout)
+            .map(|layout| {
+                self.cache_path
+                    .join(layout.entry_name(key, compression.extension()))
+            })
+            .find(|path| path.exists());
+        match found {
+            Some(path) => self
+                .relocate(&path, &target)
+                .map_err(|e| warn!("{}", e))
+                .is_ok(),
+            None => false,
+        }
+    }
+
+    /// Moves an archive to another place in the cache, removing the directories it leaves empty
+    fn relocate(&self, from: &Path, to: &Path) -> Result<()> {
+        trace!(
+            "Moving {} to {}",
+            from.to_string_lossy(),
+            to.to_string_lossy()
+        );
+        if let Some(parent) = to.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        rename_or_copy(from, to).wrap_err(format!(
+            "Cannot move {} to {}",
+            from.to_string_lossy(),
+            to.to_string_lossy()
+        ))?;
+        remove_empty_dirs(&self.cache_path, from);
+        Ok(())
+    }
+
+    fn rebuild_index(&mut self) {
+        self.walk();
+        if self.inv.is_empty() {
+            return;
+        }
+        let inv = self.inv.clone();
+        self.update_index(|index| *index = inv)
+            .unwrap_or_else(|e| warn!("Cannot write cache index: {}", e));
+    }
+
+    fn walk(&mut self) {
+        let names = match self.local.list() {
+            Ok(names) => names,
+            Err(e) => {
+                warn!("{}", e);
+                return;
+            }
+        };
+        for name in names {
+            let entry = self.cache_path.join(&name);
+            if self.parse_entry_path(&entry).is_err() {
+                if looks_like_archive(&name) {
+                    warn!(
+                        "Skipping {}, it looks like a cache entry but its name cannot be parsed",
+                        entry.to_string_lossy()
+                    );
+                } else {
+                    trace!("Skipping unrelated file {}", entry.to_string_lossy());
+                }
+                continue;
+            }
+            self.load_entry(entry.clone())
+                .wrap_err(format!(
+                    "Error while loading cache entry from {}",
+                    &entry.to_string_lossy()
+                ))
+                .unwrap_or_else(|e| warn!("{}", e));
+        }
+    }
+
+    /// Takes an advisory lock on the cache, shared for readers and exclusive for writers.
+    /// Readers of a cache that does not exist yet get no lock, as there is nothing to protect.
+    fn lock(&self, exclusive: bool, timeout: Duration) -> Result<Option<CacheLock>> {
+        let path = self.cache_path.join(".lock");
+        if self.read_only {
+            if exclusive {
+                return Err(eyre!("Cache is read-only"));
+            }
+            if !path.exists() {
+                return Ok(None);
+            }
+        }
+        if !exclusive && !self.cache_path.exists() {
+            return Ok(None);
+        }
+        std::fs::create_dir_all(&self.cache_path)?;
+        CacheLock::acquire(&path, exclusive, !self.read_only, timeout).map(Some)
+    }
+
+    /// Takes the lock of `key` that `--single-flight` runs hold while running its command, then
+    /// reloads the entries so that one cached meanwhile is found
+    fn flight_lock(&mut self, key: &CommandInputHashes, timeout: Duration) -> Result<CacheLock> {
+        let locks_dir = self.cache_path.join(LOCKS_DIR);
+        std::fs::create_dir_all(&locks_dir)?;
+        let path = locks_dir.join(format!(
+            "{:x}-{:016x}.lock",
+            key.command_hash, key.input_hash
+        ));
+        let lock = match CacheLock::acquire(&path, true, true, Duration::ZERO) {
+            Ok(lock) => lock,
+            Err(_) => {
+                info!("Waiting for another run of the same command to finish");
+                CacheLock::acquire(&path, true, true, timeout)?
+            }
+        };
+        self.reload();
+        Ok(lock)
+    }
+
+    fn hash_algo_path(&self) -> PathBuf {
+        self.cache_path.join(".hash_algo")
+    }
+
+    /// Location of an entry's file relative to the cache root, shared by local and remote caches
+    fn entry_name(&self, key: &CommandInputHashes, extension: &str) -> String {
+        self.layout.entry_name(key, extension)
+    }
+
+    fn entry_path(&self, key: &CommandInputHashes, extension: &str) -> PathBuf {
+        self.cache_path.join(self.entry_name(key, extension))
+    }
+
+    fn to_path(&self, key: &CommandInputHashes, compression: CompressionAlgo) -> PathBuf {
+        self.entry_path(key, compression.extension())
+    }
+
+    fn metadata_path(&self, key: &CommandInputHashes) -> PathBuf {
+        self.entry_path(key, METADATA_EXTENSION)
+    }
+
+    fn read_metadata(&self, path: &Path) -> Result<EntryMetadata> {
+        let file = File::open(path)?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.cache_path.join(INDEX_FILE)
+    }
+
+    fn read_index(&self) -> Result<Option<HashMap<CommandInputHashes, LastUsedAndSize>>> {
+        let file = match File::open(self.index_path()) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let index: CacheIndex = serde_json::from_reader(BufReader::new(file))?;
+        index
+            .entries
+            .into_iter()
+            .map(IndexEntry::into_entry)
+            .collect::<Result<_>>()
+            .map(Some)
+    }
+
+    /// Applies `update` to the index as currently on disk. Hits are recorded under a shared cache
+    /// lock, so the index has a lock of its own to keep concurrent updates from being lost.
+    fn update_index<F>(&self, update: F) -> Result<()>
+    where
+        F: FnOnce(&mut HashMap<CommandInputHashes, LastUsedAndSize>),
+    {
+        if self.read_only {
+            return Ok(());
+        }
+        std::fs::create_dir_all(&self.cache_path)?;
+        let _lock = CacheLock::acquire(
+            &self.cache_path.join(".index.lock"),
+            true,
+            true,
+            INDEX_LOCK_TIMEOUT,
+        )?;
+        let mut inv = match self.read_index() {
+            Ok(Some(inv)) => inv,
+            Ok(None) => self.inv.clone(),
+            Err(e) => {
+                warn!("Replacing unreadable cache index: {}", e);
+                self.inv.clone()
+            }
+        };
+        update(&mut inv);
+
+        let index = CacheIndex {
+            entries: inv.iter().map(IndexEntry::from).collect(),
+        };
+        let path = self.index_path();
+        let temp_path = temp_path(&path);
+        std::fs::write(&temp_path, serde_json::to_vec(&index)?)?;
+        std::fs::rename(&temp_path, &path)?;
+        Ok(())
+    }
+
+    fn hit_counters_path(&self) -> PathBuf {
+        self.cache_path.join(HitCounters::FILE_NAME)
+    }
+
+    fn hit_counters(&self) -> Result<HitCounters> {
+        match File::open(self.hit_counters_path()) {
+            Ok(file) => Ok(serde_json::from_reader(BufReader::new(file))?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HitCounters::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Applies `update` to the hit counters, under the index lock as lookups only hold a shared
+    /// cache lock
+    fn update_hit_counters<F>(&self, update: F) -> Result<()>
+    where
+        F: FnOnce(&mut HitCounters),
+    {
+        if self.read_only {
+            return Ok(());
+        }
+        std::fs::create_dir_all(&self.cache_path)?;
+        let _lock = CacheLock::acquire(
+            &self.cache_path.join(".index.lock"),
+            true,
+            true,
+            INDEX_LOCK_TIMEOUT,
+        )?;
+        let mut counters = self.hit_counters().unwrap_or_else(|e| {
+            warn!("Resetting unreadable hit counters: {}", e);
+            HitCounters::default()
+        });
+        update(&mut counters);
+
+        let path = self.hit_counters_path();
+        let temp_path = temp_path(&path);
+        std::fs::write(&temp_path, serde_json::to_vec(&counters)?)?;
+        std::fs::rename(&temp_path, &path)?;
+        Ok(())
+    }
+
+    /// Adds an entry whose archive is in place, both in memory and in the index. The archive
+    /// is linked with identical archives of other entries so that it is only stored once.
+    fn record_entry(&mut self, key: &CommandInputHashes, value: LastUsedAndSize) -> Result<()> {
+        if let Some(checksum) = value.checksum {
+            self.share_blob(
+                &self.to_path(key, value.compression),
+                &checksum,
+                value.compression,
+            )
+            .unwrap_or_else(|e| warn!("Cannot deduplicate cache entry: {}", e));
+        }
+        let mut released = Ok(());
+        self.update_index(|index| {
+            if let Some(previous) = index.insert(key.clone(), value) {
+                released = self.release_blob(index, &previous);
+            }
+        })?;
+        self.inv.insert(key.clone(), value);
+        released
+    }
+
+    /// Stores the `--collision-guard` digest of an entry just written
+    fn record_guard(&mut self, key: &CommandInputHashes, guard: blake3::Hash) -> Result<()> {
+        if let Some(value) = self.inv.get_mut(key) {
+            value.guard = Some(guard);
+        }
+        self.update_index(|index| {
+            if let Some(value) = index.get_mut(key) {
+                value.guard = Some(guard);
+            }
+        })
+    }
+
+    fn blob_path(&self, checksum: &blake3::Hash, compression: CompressionAlgo) -> PathBuf {
+        self.cache_path.join(BLOBS_DIR).join(format!(
+            "{}.{}",
+            checksum.to_hex(),
+            compression.extension()
+        ))
+    }
+
+    /// Makes the archive at `path` a hard link to the blob with the same content, storing it as
+    /// that blob if there is none yet
+    fn share_blob(
+        &self,
+        path: &Path,
+        checksum: &blake3::Hash,
+        compression: CompressionAlgo,
+    ) -> Result<()> {
+        let blob_path = self.blob_path(checksum, compression);
+        if blob_path.exists() {
+            trace!("Identical archive already stored, linking to it");
+            let temp_path = temp_path(path);
+            match std::fs::hard_link(&blob_path, &temp_path) {
+                // The archive already holds the same content, it only takes up more space
+                Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+                    trace!("Blob is on another filesystem, keeping the archive apart");
+                    return Ok(());
+                }
+                linked => linked?,
+            }
+            std::fs::rename(&temp_path, path)?;
+        } else {
+            std::fs::create_dir_all(self.cache_path.join(BLOBS_DIR))?;
+            match std::fs::hard_link(path, &blob_path) {
+                Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+                    trace!("Blobs are on another filesystem, copying the archive");
+                    copy_into_place(path, &blob_path)?;
+                }
+                linked => linked?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes the blob of a removed entry once no entry in `index` refers to it anymore
+    fn release_blob(
+        &self,
+        index: &HashMap<CommandInputHashes, LastUsedAndSize>,
+        removed: &LastUsedAndSize,
+    ) -> Result<()> {
+        let checksum = match removed.checksum {
+            Some(checksum) => checksum,
+            None => return Ok(()),
+        };
+        if index.values().any(|value| value.checksum == Some(checksum)) {
+            return Ok(());
+        }
+        trace!("Removing blob {}", checksum.to_hex());
+        match std::fs::remove_file(self.blob_path(&checksum, removed.compression)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn verify_checksum(&self, value: &LastUsedAndSize, cached_path: &Path) -> Result<()> {
+        let expected = match value.checksum {
+            Some(checksum) => checksum,
+            None => {
+                trace!("No checksum to verify against");
+                return Ok(());
+            }
+        };
+        let actual = archive_checksum(cached_path)?;
+        if actual != expected {
+            return Err(eyre!(
+                "Checksum mismatch, expected {} but found {}",
+                expected.to_hex(),
+                actual.to_hex()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Records a cache hit, both in memory and in the index
+    fn touch(&mut self, key: &CommandInputHashes) {
+        if self.read_only {
+            return;
+        }
+        let now = self.clock.now();
+        if let Some(val) = self.inv.get_mut(key) {
+            val.last_used = now;
+        }
+        self.update_index(|index| {
+            if let Some(val) = index.get_mut(key) {
+                val.last_used = now;
+            }
+        })
+        .unwrap_or_else(|e| warn!("Cannot record usage of cache entry: {}", e));
+    }
+
+    /// Removes the archive of an entry along with its index record, its blob unless other
+    /// entries share it, and any legacy sidecar. Directories left empty are removed too.
+    fn remove_entry_files(&self, key: &CommandInputHashes, value: &LastUsedAndSize) -> Result<()> {
+        // The archive may already be gone, the rest of the entry still has to be cleaned up
+        if self.to_path(key, value.compression).exists() {
+            self.local
+                .remove(&self.entry_name(key, value.compression.extension()))?;
+        }
+        let mut released = Ok(());
+        self.update_index(|index| {
+            if let Some(removed) = index.remove(key) {
+                released = self.release_blob(index, &removed);
+            }
+        })?;
+        released?;
+        match remove_file_and_empty_dirs(&self.cache_path, &self.metadata_path(key)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn try_restore_from_cache(
+        &mut self,
+        key: &CommandInputHashes,
+        output_paths: &[&Path],
+        options: RestoreOptions,
+    ) -> Option<Option<CapturedOutput>> {
+        if !options.dry_run && !self.read_only && !self.inv.contains_key(key) {
+            self.fetch_remote(key);
+        }
+        if let Some(val) = self.inv.get(key).copied() {
+            if val.failed && !options.cache_failures {
+                info!(
+                    hit = false,
+                    command_hash = format!("{:x}", key.command_hash),
+                    input_hash = format!("{:016x}", key.input_hash);
+                    "Ignoring cached failure, pass --cache-failures to replay it"
+                );
+                return None;
+            }
+            let cached_path = self.to_path(key, val.compression);
+            if let Some(guard) = options.guard {
+                match val.guard {
+                    Some(stored) if stored != guard => {
+                        warn!(
+                            hit = false;
+                            "Cache entry {} was stored for other inputs under the same key, \
+                            ignoring it",
+                            cached_path.to_string_lossy()
+                        );
+                        return None;
+                    }
+                    Some(_) => trace!("Collision guard matches"),
+                    None => info!(
+                        "Cache entry {} records no collision guard, restoring it unchecked",
+                        cached_path.to_string_lossy()
+                    ),
+                }
+            }
+            info!(
+                hit = true,
+                command_hash = format!("{:x}", key.command_hash),
+                input_hash = format!("{:016x}", key.input_hash),
+                archive_size = val.size;
+                "Found cached entry, copying {}",
+                cached_path.to_string_lossy()
+            );
+            if !options.dry_run {
+                if let Err(e) = self.verify_checksum(&val, &cached_path) {
+                    warn!(
+                        "{} corrupt cache entry {}: {}",
+                        if self.read_only {
+                            "Ignoring"
+                        } else {
+                            "Discarding"
+                        },
+                        cached_path.to_string_lossy(),
+                        e
+                    );
+                    if self.read_only {
+                        return None;
+                    }
+                    if let Err(e) = self.remove_entry_files(key, &val) {
+                        warn!("{}", e);
+                    }
+                    self.inv.remove(key);
+                    return None;
+                }
+                let reader = match self.archive_reader(&val, options.max_size) {
+                    Ok(reader) => reader,
+                    Err(e) => {
+                        warn!(
+                            "Ignoring cache entry {}: {}",
+                            cached_path.to_string_lossy(),
+                            e
+                        );
+                        return None;
+                    }
+                };
+                let expected_digest = match val.output_digest {
+                    _ if !options.verify || val.failed => None,
+                    None => {
+                        warn!(
+                            "Cache entry {} records no output digest, the restore is not verified",
+                            cached_path.to_string_lossy()
+                        );
+                        None
+                    }
+                    digest => digest,
+                };
+                let result = Self::unpack(
+                    &cached_path,
+                    reader,
+                    output_paths,
+                    options.capture,
+                    options.cache_failures,
+                    expected_digest,
+                )
+                .and_then(|captured| {
+                    if options.touch && !val.failed {
+                        touch_outputs(output_paths)?;
+                    }
+                    Ok(captured)
+                })
+                .map_err(|e| warn!("{:#}", e));
+                if result.is_ok() {
+                    self.touch(key);
+                }
+                return result.ok();
+            }
+            // Nothing is restored, but the entry would have been
+            return Some(None);
+        }
+        info!(
+            hit = false,
+            command_hash = format!("{:x}", key.command_hash),
+            input_hash = format!("{:016x}", key.input_hash);
+            "No such cached entry: {}",
+            self.to_path(key, self.compression).to_string_lossy()
+        );
+        None
+    }
+
+    /// Copies an entry missing from the local cache out of the remote one, if any. Entries are
+    /// written atomically, so this is safe under a shared lock
+    fn fetch_remote(&mut self, key: &CommandInputHashes) -> bool {
+        let remote = match &self.remote {
+            Some(remote) => remote,
+            None => return false,
+        };
+        let mut codecs = vec![self.compression];
+        codecs.extend(
+            CompressionAlgo::VARIANTS
+                .iter()
+                .filter_map(|variant| variant.parse::<CompressionAlgo>().ok())
+                .filter(|codec| *codec != self.compression),
+        );
+        for compression in codecs {
+            let name = self.entry_name(key, compression.extension());
+            match remote.get(&name) {
+                Ok(None) => continue,
+                Ok(Some(mut reader)) => {
+                    info!("Fetching {} from the remote cache", name);
+                    let path = self.to_path(key, compression);
+                    let fetched = self
+                        .local
+                        .put(&name, &mut reader)
+                        .and_then(|_| archive_checksum(&path))
+                        .and_then(|checksum| {
+                            Ok(LastUsedAndSize {
+                                last_used: self.clock.now(),
+                                size: path.metadata()?.len(),
+                                compression,
+                                checksum: Some(checksum),
+                                output_digest: None,
+                                failed: false,
+                                dictionary: ZstdDictionary::recorded_in(&path, compression)?,
+                                guard: None,
+                            })
+                        })
+                        .and_then(|value| self.record_entry(key, value));
+                    return fetched
+                        .map_err(|e| warn!("Cannot fetch from the remote cache: {}", e))
+                        .is_ok();
+                }
+                Err(e) => {
+                    warn!("Cannot reach the remote cache: {}", e);
+                    return false;
+                }
+            }
+        }
+        trace!("No such entry in the remote cache either");
+        false
+    }
+
+    /// Restores the outputs, returning the captured command output when `capture` is set. With
+    /// `expected_digest`, the restored files are read back and compared to it. The archive is
+    /// streamed, so memory stays bounded whatever its size, and `max_size` bounds the disk used.
+    fn unpack(
+        cached_path: &Path,
+        reader: ArchiveReader,
+        output_paths: &[&Path],
+        capture: bool,
+        cache_failures: bool,
+        expected_digest: Option<blake3::Hash>,
+    ) -> Result<Option<CapturedOutput>> {
+        let mut archive = tar::Archive::new(reader.open(cached_path)?);
+        let mut entries = archive.entries()?;
+        let manifest: ArchiveManifest = match entries.next() {
+            // Only legacy archives of an empty output directory hold no member at all
+            None => {
+                if capture {
+                    return Err(eyre!("Cached entry has no captured output"));
+                }
+                Self::unpack_legacy(cached_path, reader, output_paths)?;
+                return Ok(None);
+            }
+            Some(entry) => {
+                let entry = entry?;
+                if entry.path()? != Path::new(MANIFEST_NAME) {
+                    if capture {
+                        return Err(eyre!("Cached entry has no captured output"));
+                    }
+                    Self::unpack_legacy(cached_path, reader, output_paths)?;
+                    return Ok(None);
+                }
+                serde_json::from_reader(entry)?
+            }
+        };
+        // Failed runs hold no outputs, only their exit code and captured streams
+        let failed = manifest.exit_code.is_some_and(|exit_code| exit_code != 0);
+        if failed && !cache_failures {
+            return Err(eyre!("Cached entry records a failed run"));
+        }
+        if !failed && manifest.outputs.len() != output_paths.len() {
+            return Err(eyre!(
+                "Cached entry holds {} outputs but {} were requested",
+                manifest.outputs.len(),
+                output_paths.len()
+            ));
+        }
+        let mut captured = match (capture || failed, manifest.exit_code) {
+            (false, _) => None,
+            (true, Some(exit_code)) => Some(CapturedOutput {
+                exit_code,
+                ..CapturedOutput::default()
+            }),
+            (true, None) => return Err(eyre!("Cached entry has no captured output")),
+        };
+
+        let mut directories = Vec::new();
+        let mut restored = Vec::new();
+        for entry in entries {
+            let mut entry = entry?;
+            let member = entry.path()?.into_owned();
+            if member == Path::new(STDOUT_NAME) || member == Path::new(STDERR_NAME) {
+                if let Some(captured) = captured.as_mut() {
+                    let stream = if member == Path::new(STDOUT_NAME) {
+                        &mut captured.stdout
+                    } else {
+                        &mut captured.stderr
+                    };
+                    entry.read_to_end(stream)?;
+                }
+                continue;
+            }
+            let mut components = member.components();
+            let output_path = components
+                .next()
+                .and_then(|prefix| prefix.as_os_str().to_str())
+                .and_then(|prefix| prefix.parse::<usize>().ok())
+                .and_then(|index| output_paths.get(index))
+                .ok_or_else(|| eyre!("Unexpected archive member {}", member.display()))?;
+            let relative = components.as_path();
+            if relative
+                .components()
+                .any(|component| !matches!(component, Component::Normal(_)))
+            {
+                return Err(eyre!("Refusing to unpack {}", member.display()));
+            }
+
+            let destination = if relative.as_os_str().is_empty() {
+                output_path.to_path_buf()
+            } else {
+                output_path.join(relative)
+            };
+            if let Some(parent) = destination.parent().filter(|p| !p.as_os_str().is_empty()) {
+                std::fs::create_dir_all(parent)?;
+            }
+            // Directories, empty ones included, are created right away but only get their
+            // permissions once their contents are restored, in case they are read-only
+            entry.set_preserve_mtime(!manifest.reproducible);
+            let mtime = exact_mtime(&mut entry)?;
+            if expected_digest.is_some() {
+                restored.push((member, destination.clone()));
+            }
+            if entry.header().entry_type().is_dir() {
+                std::fs::create_dir_all(&destination)?;
+                directories.push((entry, destination, mtime));
+                continue;
+            }
+            entry.unpack(&destination)?;
+            if let Some(mtime) = mtime {
+                filetime::set_symlink_file_times(&destination, mtime, mtime)?;
+            }
+        }
+        for (mut entry, destination, mtime) in directories {
+            entry.unpack(&destination)?;
+            if let Some(mtime) = mtime {
+                filetime::set_file_times(&destination, mtime, mtime)?;
+            }
+        }
+
+        if let Some(expected) = expected_digest {
+            let mut digest = OutputDigest::default();
+            for (member, destination) in &restored {
+                let metadata = destination.symlink_metadata()?;
+                digest.add_member(member, destination, &metadata)?;
+                if metadata.is_file() {
+                    std::io::copy(&mut File::open(destination)?, &mut digest.0)?;
+                }
+            }
+            if digest.0.finalize() != expected {
+                return Err(eyre!(
+                    "Restored outputs differ from the cached entry {}",
+                    cached_path.to_string_lossy()
+                ));
+            }
+            trace!("Restored outputs match the cached entry");
+        }
+        Ok(captured)
+    }
+
+    /// Archives written before output manifests hold a single output at their root: the contents
+    /// of a directory, or a file stored under its own name
+    fn unpack_legacy(
+        cached_path: &Path,
+        reader: ArchiveReader,
+        output_paths: &[&Path],
+    ) -> Result<()> {
+        let output_path = match output_paths {
+            [output_path] => output_path,
+            _ => return Err(eyre!("Cached entry holds a single output")),
+        };
+        let file_name = output_path.file_name().map(Path::new);
+        let mut archive = tar::Archive::new(reader.open(cached_path)?);
+        let mut directories = Vec::new();
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if entry.header().entry_type().is_file() && Some(&*entry.path()?) == file_name {
+                if let Some(parent) = output_path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                    std::fs::create_dir_all(parent)?;
+                }
+                entry.unpack(output_path)?;
+            } else if entry.header().entry_type().is_dir() {
+                directories.push(entry);
+            } else {
+                std::fs::create_dir_all(output_path)?;
+                entry.unpack_in(output_path)?;
+            }
+        }
+        for mut entry in directories {
+            std::fs::create_dir_all(output_path)?;
+            entry.unpack_in(output_path)?;
+        }
+        if output_path.symlink_metadata().is_err() {
+            std::fs::create_dir_all(output_path)?;
+        }
+        Ok(())
+    }
+
+    fn write_to_cache(
+        &mut self,
+        output_paths: &[&Path],
+        key: &CommandInputHashes,
+        captured: Option<&CapturedOutput>,
+        jobs: usize,
+        times: ArchiveTimes,
+        progress: &ProgressBar,
+    ) -> Result<u64> {
+        // An output that is missing after a successful run is more likely a mistake in the
+        // command line than something worth restoring as an empty directory
+        for output_path in output_paths {
+            if output_path.symlink_metadata().is_err() {
+                return Err(eyre!(
+                    "Command did not produce its output {}, nothing was cached",
+                    output_path.to_string_lossy()
+                ));
+            }
+        }
+        let compression = self.compression_for(output_paths)?;
+        let cached_path = self.to_path(key, compression);
+        trace!(
+            "Copying results {:?} to cache {}",
+            output_paths,
+            cached_path.to_string_lossy()
+        );
+
+        std::fs::create_dir_all(cached_path.parent().unwrap())?;
+        if !self.hash_algo_path().exists() {
+            std::fs::write(self.hash_algo_path(), self.hash_algo.to_string())?;
+        }
+
+        if let Some(previous) = self.inv.get(key) {
+            if previous.compression != compression {
+                trace!("Replacing entry stored with {}", previous.compression);
+                std::fs::remove_file(self.to_path(key, previous.compression))?;
+            }
+        }
+
+        let temp_path = temp_path(&cached_path);
+        let written = File::create(&temp_path)
+            .map_err(Report::from)
+            .and_then(|file| {
+                self.write_archive(
+                    output_paths,
+                    captured,
+                    progress.wrap_write(file),
+                    jobs,
+                    times,
+                    compression,
+                )
+            })
+            .and_then(|digest| Ok((archive_checksum(&temp_path)?, digest)));
+        let (checksum, output_digest) = match written {
+            Ok(written) => written,
+            Err(e) => {
+                std::fs::remove_file(&temp_path).unwrap_or_else(|e| warn!("{}", e));
+                return Err(e);
+            }
+        };
+        progress.finish_and_clear();
+        std::fs::rename(&temp_path, &cached_path)?;
+
+        let size = cached_path.metadata()?.len();
+        self.record_entry(
+            key,
+            LastUsedAndSize {
+                last_used: self.clock.now(),
+                size,
+                compression,
+                checksum: Some(checksum),
+                output_digest: Some(output_digest),
+                failed: captured.is_some_and(|captured| captured.exit_code != 0),
+                dictionary: self.dictionary_for(compression),
+                guard: None,
+            },
+        )?;
+
+        if let Some(remote) = &self.remote {
+            let name = self.entry_name(key, compression.extension());
+            trace!("Uploading {} to the remote cache", name);
+            self.local
+                .get(&name)
+                .and_then(|archive| match archive {
+                    Some(mut archive) => remote.put(&name, &mut archive),
+                    None => Ok(()),
+                })
+                .unwrap_or_else(|e| warn!("Cannot upload to the remote cache: {}", e));
+        }
+
+        info!(
+            command_hash = format!("{:x}", key.command_hash),
+            input_hash = format!("{:016x}", key.input_hash),
+            archive_size = size;
+            "Cached results in {} ({})",
+            cached_path.to_string_lossy(),
+            bytefmt::format(size)
+        );
+        Ok(size)
+    }
+
+    /// Codec to write an entry with, none when the outputs would barely shrink
+    fn compression_for(&self, output_paths: &[&Path]) -> Result<CompressionAlgo> {
+        let threshold = match self.compress_threshold {
+            Some(threshold) if self.compression != CompressionAlgo::None => threshold,
+            _ => return Ok(self.compression),
+        };
+        match compressibility(output_paths)? {
+            Some(ratio) if ratio > threshold => {
+                info!(
+                    "Outputs only compress to {:.0}% of their size, storing them uncompressed",
+                    ratio * 100.0
+                );
+                Ok(CompressionAlgo::None)
+            }
+            _ => Ok(self.compression),
+        }
+    }
+
+    fn write_archive<W: Write + Send + 'static>(
+        &self,
+        output_paths: &[&Path],
+        captured: Option<&CapturedOutput>,
+        file: W,
+        jobs: usize,
+        times: ArchiveTimes,
+        compression: CompressionAlgo,
+    ) -> Result<blake3::Hash> {
+        match compression {
+            CompressionAlgo::None => {
+                let (_, digest) = Self::stream_outputs(output_paths, captured, file, times)?;
+                Ok(digest)
+            }
+            CompressionAlgo::Gzip => {
+                let level = self
+                    .compression_level
+                    .map(Compression::new)
+                    .unwrap_or_default();
+                let encoder = ZBuilder::<Gzip, _>::new()
+                    .num_threads(jobs)
+                    .compression_level(level)
+                    .from_writer(file);
+                let (mut encoder, digest) =
+                    Self::stream_outputs(output_paths, captured, encoder, times)?;
+                encoder.finish()?;
+                Ok(digest)
+            }
+            CompressionAlgo::Zstd => {
+                let level = self.compression_level.unwrap_or(0) as i32;
+                let mut encoder = match &self.dictionary {
+                    Some(dictionary) => {
+                        let mut file = file;
+                        dictionary.write_frame(&mut file)?;
+                        zstd::Encoder::with_dictionary(file, level, &dictionary.data)?
+                    }
+                    None => zstd::Encoder::new(file, level)?,
+                };
+                if jobs > 1 {
+                    encoder.multithread(jobs as u32)?;
+                }
+                let (encoder, digest) =
+                    Self::stream_outputs(output_paths, captured, encoder, times)?;
+                encoder.finish()?;
+                Ok(digest)
+            }
+        }
+    }
+
+    /// Tars the outputs on another thread, so that reading them overlaps with compressing and
+    /// writing the archive. The bytes are the same as those of `append_outputs`.
+    fn stream_outputs<W: Write>(
+        output_paths: &[&Path],
+        captured: Option<&CapturedOutput>,
+        mut writer: W,
+        times: ArchiveTimes,
+    ) -> Result<(W, blake3::Hash)> {
+        let (sender, receiver) = std::sync::mpsc::sync_channel(TAR_CHANNEL_CHUNKS);
+        std::thread::scope(|scope| {
+            let archiver = scope.spawn(move || -> Result<blake3::Hash> {
+                let chunks = BufWriter::with_capacity(TAR_CHUNK_SIZE, ChannelWriter(sender));
+                let (mut chunks, digest) =
+                    Self::append_outputs(output_paths, captured, chunks, times)?;
+                chunks.flush()?;
+                Ok(digest)
+            });
+            let written = receiver
+                .iter()
+                .try_for_each(|chunk| writer.write_all(&chunk));
+            // Unblocks the archiver if writing failed
+            drop(receiver);
+            let archived = archiver
+                .join()
+                .map_err(|_| eyre!("Archiving thread panicked"))?;
+            written?;
+            Ok((writer, archived?))
+        })
+    }
+
+    /// Writes the manifest and captured streams, followed by each output under its index as a
+    /// prefix. Returns the digest of the outputs along with the writer.
+    fn append_outputs<W: Write>(
+        output_paths: &[&Path],
+        captured: Option<&CapturedOutput>,
+        writer: W,
+        times: ArchiveTimes,
+    ) -> Result<(W, blake3::Hash)> {
+        let mut tar = tar::Builder::new(writer);
+        let mut append_file = |name: &str, data: &[u8]| {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, name, data)
+        };
+
+        let manifest = serde_json::to_vec(&ArchiveManifest {
+            outputs: output_paths.iter().map(|path| path.to_path_buf()).collect(),
+            exit_code: captured.map(|captured| captured.exit_code),
+            reproducible: times == ArchiveTimes::Zeroed,
+        })?;
+        append_file(MANIFEST_NAME, &manifest)?;
+        if let Some(captured) = captured {
+            append_file(STDOUT_NAME, &captured.stdout)?;
+            append_file(STDERR_NAME, &captured.stderr)?;
+        }
+
+        let mut digest = OutputDigest::default();
+        for (index, output_path) in output_paths.iter().enumerate() {
+            Self::append_sorted(
+                &mut tar,
+                &mut digest,
+                output_path,
+                Path::new(&index.to_string()),
+                times,
+            )?;
+        }
+        Ok((tar.into_inner()?, digest.0.finalize()))
+    }
+
+    /// Appends `path` under `name`, followed by everything below it sorted by path so that the
+    /// layout does not depend on the filesystem
+    fn append_sorted<W: Write>(
+        tar: &mut tar::Builder<W>,
+        digest: &mut OutputDigest,
+        path: &Path,
+        name: &Path,
+        times: ArchiveTimes,
+    ) -> Result<()> {
+        for entry in WalkDir::new(path).sort_by_file_name() {
+            let entry = entry?;
+            let relative = entry.path().strip_prefix(path)?;
+            let member = if relative.as_os_str().is_empty() {
+                name.to_path_buf()
+            } else {
+                name.join(relative)
+            };
+
+            let metadata = entry.path().symlink_metadata()?;
+            let mut header = tar::Header::new_gnu();
+            header.set_metadata(&metadata);
+            match times {
+                ArchiveTimes::Seconds => {}
+                ArchiveTimes::Exact => {
+                    // Headers only hold whole seconds, the exact time goes in a PAX record
+                    let mtime = metadata.modified()?.duration_since(SystemTime::UNIX_EPOCH);
+                    if let Ok(mtime) = mtime {
+                        let value = format!("{}.{:09}", mtime.as_secs(), mtime.subsec_nanos());
+                        tar.append_pax_extensions([("mtime", value.as_bytes())])?;
+                    }
+                }
+                ArchiveTimes::Zeroed => {
+                    header.set_mtime(0);
+                    header.set_uid(0);
+                    header.set_gid(0);
+                }
+            }
+            digest.add_member(&member, entry.path(), &metadata)?;
+            // Links are stored as such rather than replaced by copies of their targets
+            if metadata.file_type().is_symlink() {
+                tar.append_link(&mut header, member, std::fs::read_link(entry.path())?)?;
+            } else if metadata.is_file() {
+                let content = DigestReader(File::open(entry.path())?, &mut digest.0);
+                tar.append_data(&mut header, member, content)?;
+            } else {
+                tar.append_data(&mut header, member, std::io::empty())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Size of the archives on disk, counting blobs shared between entries once
+    fn disk_size(&self) -> u64 {
+        let mut seen = HashSet::new();
+        self.inv
+            .values()
+            .filter(|value| value.checksum.is_none_or(|checksum| seen.insert(checksum)))
+            .map(|value| value.size)
+            .sum()
+    }
+
+    fn stats(&self, top: usize) -> CacheStats {
+        let mut entries: Vec<(&CommandInputHashes, &LastUsedAndSize)> = self.inv.iter().collect();
+        entries.sort_by_key(|(_, value)| std::cmp::Reverse(value.size));
+
+        CacheStats {
+            entries: self.inv.len(),
+            total_size: self.disk_size(),
+            oldest_last_used: self
+                .inv
+                .values()
+                .map(|value| value.last_used)
+                .min()
+                .map(format_time),
+            newest_last_used: self
+                .inv
+                .values()
+                .map(|value| value.last_used)
+                .max()
+                .map(format_time),
+            largest: entries
+                .into_iter()
+                .take(top)
+                .map(|(key, value)| CacheEntryStats {
+                    path: self.to_path(key, value.compression),
+                    size: value.size,
+                    last_used: format_time(value.last_used),
+                })
+                .collect(),
+        }
+    }
+
+    /// Entries of at least `min_size`, in the given order
+    fn list(&self, sort: ListSort, min_size: u64) -> Vec<(CommandInputHashes, LastUsedAndSize)> {
+        let mut entries: Vec<(CommandInputHashes, LastUsedAndSize)> = self
+            .inv
+            .iter()
+            .filter(|(_, value)| value.size >= min_size)
+            .map(|(key, value)| (key.clone(), *value))
+            .collect();
+        match sort {
+            ListSort::Size => entries.sort_by_key(|(_, value)| std::cmp::Reverse(value.size)),
+            ListSort::Age => entries.sort_by_key(|(_, value)| value.last_used),
+        }
+        entries
+    }
+
+    /// Deletes every file under the cache path that does not belong to an entry, then any
+    /// directory left empty. Archives missing from the index are added back to it instead.
+    /// Returns the number of files removed and their total size.
+    fn gc(&mut self) -> Result<(usize, u64)> {
+        if !self.cache_path.exists() {
+            return Ok((0, 0));
+        }
+        if !self.index_path().exists() && !self.hash_algo_path().exists() {
+            return Err(eyre!(
+                "Refusing to collect garbage in {}, it does not look like a cache",
+                self.cache_path.to_string_lossy()
+            ));
+        }
+        let root_files = [
+            ".lock",
+            ".index.lock",
+            ".hash_algo",
+            INDEX_FILE,
+            FileHashCache::FILE_NAME,
+            HitCounters::FILE_NAME,
+        ];
+
+        let blobs_dir = self.cache_path.join(BLOBS_DIR);
+        let locks_dir = self.cache_path.join(LOCKS_DIR);
+        let blobs: HashSet<PathBuf> = self
+            .inv
+            .values()
+            .filter_map(|value| {
+                value
+                    .checksum
+                    .map(|checksum| self.blob_path(&checksum, value.compression))
+            })
+            .collect();
+
+        let mut removed = 0usize;
+        let mut removed_size = 0u64;
+        let mut adopted = Vec::new();
+        for entry in WalkDir::new(&self.cache_path)
+            .min_depth(1)
+            .contents_first(true)
+        {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type().is_dir() {
+                if std::fs::read_dir(path)?.next().is_none() {
+                    trace!("Removing empty directory {}", path.to_string_lossy());
+                    std::fs::remove_dir(path)?;
+                }
+                continue;
+            }
+
+            let name = entry.file_name().to_string_lossy();
+            let keep = match entry.depth() {
+                1 => root_files.contains(&name.as_ref()),
+                // Entries are hard links to their blob, so this frees nothing they still use
+                2 if path.parent() == Some(&blobs_dir) => blobs.contains(path),
+                // Only locks no run holds can go, this takes them for the time they are removed
+                2 if path.parent() == Some(&locks_dir) => {
+                    match CacheLock::acquire(path, true, true, Duration::ZERO) {
+                        Ok(lock) => {
+                            trace!("Removing unused lock {}", path.to_string_lossy());
+                            std::fs::remove_file(path)?;
+                            drop(lock);
+                            continue;
+                        }
+                        Err(_) => true,
+                    }
+                }
+                _ if is_temp_path(path) => false,
+                _ if path.extension() == Some(METADATA_EXTENSION.as_ref()) => {
+                    // Sidecars are only worth keeping while their archive is
+                    let stem = path.with_extension("");
+                    CompressionAlgo::VARIANTS
+                        .iter()
+                        .filter_map(|variant| variant.parse::<CompressionAlgo>().ok())
+                        .any(|codec| stem.with_extension(codec.extension()).exists())
+                }
+                _ => match self.parse_entry_path(path) {
+                    Err(_) => false,
+                    // The entry was moved into the current layout, this is a leftover copy
+                    Ok((layout, key, _))
+                        if layout != self.layout && self.inv.contains_key(&key) =>
+                    {
+                        false
+                    }
+                    Ok((_, key, _)) => {
+                        if !self.inv.contains_key(&key) {
+                            adopted.push(path.to_owned());
+                        }
+                        true
+                    }
+                },
+            };
+            if !keep {
+                info!("Removing stray file {}", path.to_string_lossy());
+                removed_size += entry.metadata().map(|m| m.len()).unwrap_or(0);
+                std::fs::remove_file(path)?;
+                removed += 1;
+            }
+        }
+
+        if !adopted.is_empty() {
+            for path in adopted {
+                info!("Adding {} back to the cache index", path.to_string_lossy());
+                self.load_entry(path)?;
+            }
+            let inv = self.inv.clone();
+            self.update_index(|index| *index = inv)?;
+        }
+        Ok((removed, removed_size))
+    }
+
+    fn older_than(&self, age: Option<Duration>) -> Vec<(CommandInputHashes, LastUsedAndSize)> {
+        let now = self.clock.now();
+        self.inv
+            .iter()
+            .filter(|(_, value)| match age {
+                Some(age) => now
+                    .duration_since(value.last_used)
+                    .map(|since| since > age)
+                    .unwrap_or(false),
+                None => true,
+            })
+            .map(|(key, value)| (key.clone(), *value))
+            .collect()
+    }
+
+    fn clear(&mut self, older_than: Option<Duration>) -> Result<(usize, u64)> {
+        let expired = self.older_than(older_than);
+
+        let mut removed_size = 0u64;
+        for (key, value) in &expired {
+            let path = self.to_path(key, value.compression);
+            trace!("Removing {}", path.to_string_lossy());
+            self.remove_entry_files(key, value)?;
+            self.inv.remove(key);
+            removed_size += value.size;
+        }
+        Ok((expired.len(), removed_size))
+    }
+
+    /// Dictionary new archives with this compression are written with
+    fn dictionary_for(&self, compression: CompressionAlgo) -> Option<blake3::Hash> {
+        match (compression, &self.dictionary) {
+            (CompressionAlgo::Zstd, Some(dictionary)) => Some(dictionary.hash),
+            _ => None,
+        }
+    }
+
+    /// How to read the archive of an entry, failing when it needs a dictionary other than `--dict`
+    fn archive_reader(
+        &self,
+        value: &LastUsedAndSize,
+        max_size: Option<u64>,
+    ) -> Result<ArchiveReader<'_>> {
+        let dictionary = match (value.dictionary, &self.dictionary) {
+            (None, _) => None,
+            (Some(hash), Some(dictionary)) if hash == dictionary.hash => Some(&*dictionary.data),
+            (Some(_), _) => {
+                return Err(eyre!(
+                    "it was compressed with another dictionary than the one given with --dict"
+                ))
+            }
+        };
+        Ok(ArchiveReader {
+            compression: value.compression,
+            dictionary,
+            max_size,
+        })
+    }
+
+    /// Reads every entry through, reporting the corrupt ones. With `repair`, they are removed.
+    fn verify(&mut self, repair: bool) -> Result<VerifyReport> {
+        let mut entries: Vec<(CommandInputHashes, LastUsedAndSize)> = self
+            .inv
+            .iter()
+            .map(|(key, value)| (key.clone(), *value))
+            .collect();
+        entries.sort_by_key(|(key, _)| (key.command_hash, key.input_hash));
+
+        let mut report = VerifyReport::default();
+        for (key, value) in entries {
+            let path = self.to_path(&key, value.compression);
+            let reader = match self.archive_reader(&value, None) {
+                Ok(reader) => reader,
+                Err(e) => {
+                    report.unchecked.push((path, e.to_string()));
+                    continue;
+                }
+            };
+            report.checked += 1;
+            let checked = self
+                .verify_checksum(&value, &path)
+                .and_then(|_| Self::read_archive(&path, reader));
+            if let Err(e) = checked {
+                if repair {
+                    self.remove_entry_files(&key, &value)?;
+                    self.inv.remove(&key);
+                }
+                report.corrupt.push((path, format!("{:#}", e)));
+            }
+        }
+        Ok(report)
+    }
+
+    /// Decompresses an archive and reads each of its members, without unpacking them
+    fn read_archive(path: &Path, reader: ArchiveReader) -> Result<()> {
+        let mut archive = tar::Archive::new(reader.open(path)?);
+        for entry in archive.entries()? {
+            std::io::copy(&mut entry?, &mut std::io::sink())?;
+        }
+        Ok(())
+    }
+
+    /// Evicts least recently used entries other than `new_key` until the cache fits the limits.
+    /// Sizes are those of the archives on disk.
+    fn discard_until(
+        &mut self,
+        new_key: &CommandInputHashes,
+        limit: u64,
+        max_entries: Option<usize>,
+    ) -> Result<()> {
+        if self.discard_entries_until(new_key, limit, max_entries)? {
+            return Ok(());
+        }
+        // Entries were deleted behind our back, so the loaded inventory no longer adds up
+        warn!("Ran out of cache entries to evict, the cache was changed mid-run, rescanning it");
+        self.reload();
+        if !self.discard_entries_until(new_key, limit, max_entries)? {
+            warn!("Cache is still over its limits after rescanning it");
+        }
+        Ok(())
+    }
+
+    /// Evicts the least recently used entries, returning false if they ran out before the cache
+    /// got within limits
+    fn discard_entries_until(
+        &mut self,
+        new_key: &CommandInputHashes,
+        limit: u64,
+        max_entries: Option<usize>,
+    ) -> Result<bool> {
+        let new_entry = match self.inv.get(new_key) {
+            Some(value) => *value,
+            None => return Ok(true),
+        };
+        let output_size = new_entry.size;
+        if output_size >= limit {
+            warn!("Output is larger than cache size, will not cache");
+            return self.evict(new_key, &new_entry).map(|_| true);
+        }
+
+        trace!("Assuring cache is within limits");
+        // Entries sharing a blob only take up its size once
+        let mut blob_refs: HashMap<blake3::Hash, usize> = HashMap::new();
+        let mut cache_size = 0u64;
+        let mut cache_entries: Vec<(CommandInputHashes, LastUsedAndSize)> = Vec::new();
+        for (key, value) in &self.inv {
+            let refs = value
+                .checksum
+                .map(|checksum| blob_refs.entry(checksum).or_default());
+            match refs {
+                Some(refs) if *refs > 0 => *refs += 1,
+                Some(refs) => {
+                    *refs = 1;
+                    cache_size += value.size;
+                }
+                None => cache_size += value.size,
+            }
+            if key != new_key {
+                cache_entries.push((key.clone(), *value));
+            }
+        }
+        cache_entries.sort_by_key(|p| std::cmp::Reverse(p.1.last_used));
+
+        // The new entry counts towards both limits
+        while cache_size >= limit || max_entries.is_some_and(|max| cache_entries.len() >= max) {
+            let (key, value) = match cache_entries.pop() {
+                Some(entry) => entry,
+                None => return Ok(false),
+            };
+            self.evict(&key, &value)?;
+            let freed = match value
+                .checksum
+                .and_then(|checksum| blob_refs.get_mut(&checksum))
+            {
+                Some(refs) => {
+                    *refs -= 1;
+                    *refs == 0
+                }
+                None => true,
+            };
+            if freed {
+                cache_size = cache_size.saturating_sub(value.size);
+            }
+        }
+        Ok(true)
+    }
+
+    fn evict(&mut self, key: &CommandInputHashes, value: &LastUsedAndSize) -> Result<()> {
+        trace!(
+            "Removing {} with size: {:?}, last_used: {:?}",
+            self.to_path(key, value.compression).to_string_lossy(),
+            &value.size,
+            &value
+                .last_used
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_else(|_| Duration::new(0, 0)),
+        );
+        self.inv.remove(key);
+        let path = self.to_path(key, value.compression);
+        if !path.exists() {
+            // Removed by someone else since it was loaded, it only has to leave the index
+            warn!(
+                "Cache entry {} is already gone, skipping it",
+                path.to_string_lossy()
+            );
+        }
+        self.remove_entry_files(key, value).map_err(|e| {
+            warn!("{}", e);
+            e
+        })
+    }
+}
+
+/// Key of a cache entry
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CommandInputHashes {
+    /// Hash of the command line, along with the environment and working directory if asked to
+    pub command_hash: u64,
+    /// Hash of the contents of the inputs, or of `--key`
+    pub input_hash: u64,
+}
+
+impl Config {
+    /// Parses command line style arguments, the first one being the program name, filling in
+    /// options they leave unset from the config file
+    pub fn parse_args<I, T>(args: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let opt = Self::from_matches(&Self::clap().get_matches_from_safe(args)?)?;
+        if let Some((description, _)) = opt.usage_error() {
+            return Err(eyre!(description));
+        }
+        Ok(opt)
+    }
+
+    /// Parses the command line, filling in options it leaves unset from the config file
+    fn from_args_and_config() -> Result<Self> {
+        Self::from_matches(&Self::clap().get_matches())
+    }
+
+    fn from_matches(matches: &ArgMatches) -> Result<Self> {
+        let mut opt = Self::from_clap(matches);
+        if let Some(path) = FileConfig::discover()? {
+            FileConfig::load(&path)?.merge_into(&mut opt, matches, &path)?;
+            opt.config_file = Some(path);
+        }
+        if let Some(path) = &opt.command_file {
+            if !opt.command.is_empty() {
+                return Err(eyre!(
+                    "The command cannot be given along with --command-file"
+                ));
+            }
+            opt.command = Self::read_command_file(path)?;
+        }
+        opt.resolve_paths()?;
+        Ok(opt)
+    }
+
+    /// Makes input and output paths absolute, so that inputs are walked in the same order and
+    /// outputs get the same archive prefixes whichever directory folca runs from. Inputs are
+    /// canonicalized when they exist, outputs are only resolved lexically so that a symlinked
+    /// output stays a link rather than being replaced by what it points to.
+    fn resolve_paths(&mut self) -> Result<()> {
+        let resolve_input = |path: &Path| match std::fs::canonicalize(path) {
+            Ok(canonical) => Ok(canonical),
+            Err(_) => absolute_path(path),
+        };
+        if let Some(input_path) = &mut self.input_path {
+            *input_path = resolve_input(input_path)?;
+        }
+        for input in &mut self.input {
+            *input = resolve_input(input)?;
+        }
+        if let Some(output_path) = &mut self.output_path {
+            *output_path = absolute_path(output_path)?;
+        }
+        for output in &mut self.output {
+            *output = absolute_path(output)?;
+        }
+        Ok(())
+    }
+
+    /// Arguments of the command, one per line, so that they hash as if given on the command line
+    fn read_command_file(path: &Path) -> Result<Vec<String>> {
+        let contents = if path == Path::new("-") {
+            let mut contents = String::new();
+            std::io::stdin()
+                .read_to_string(&mut contents)
+                .wrap_err("Cannot read the command from stdin")?;
+            contents
+        } else {
+            std::fs::read_to_string(path).wrap_err(format!(
+                "Cannot read command file {}",
+                path.to_string_lossy()
+            ))?
+        };
+        Ok(contents.lines().map(str::to_string).collect())
+    }
+
+    /// Checks the arguments clap cannot, as they depend on the subcommand
+    fn usage_error(&self) -> Option<(&'static str, ClapErrorKind)> {
+        if self.print_key && (self.subcommand.is_some() || self.command.is_empty()) {
+            Some((PRINT_KEY_WITHOUT_RUN, ClapErrorKind::ArgumentConflict))
+        } else if self.subcommand.is_none() && !self.gc && self.command.is_empty() {
+            Some((MISSING_REQUIRED, ClapErrorKind::MissingRequiredArgument))
+        } else {
+            None
+        }
+    }
+
+    fn non_zero_bytes(input: &str) -> Result<u64, &'static str> {
+        let parsed = bytefmt::parse(input)?;
+        if parsed == 0 {
+            Err("Size cannot be zero")
+        } else {
+            Ok(parsed)
+        }
+    }
+
+    fn input_path(&self) -> &Path {
+        self.input_path
+            .as_deref()
+            .expect("input path is required without a subcommand")
+    }
+
+    fn input_paths(&self) -> Vec<&Path> {
+        let mut input_paths: Vec<&Path> = std::iter::once(self.input_path())
+            .chain(self.input.iter().map(PathBuf::as_path))
+            .collect();
+        input_paths.sort();
+        input_paths.dedup();
+        input_paths
+    }
+
+    fn output_path(&self) -> &Path {
+        self.output_path
+            .as_deref()
+            .expect("output path is required without a subcommand")
+    }
+
+    /// Sorted so that outputs map to the same archive prefixes however they were passed
+    fn output_paths(&self) -> Vec<&Path> {
+        let mut output_paths: Vec<&Path> = std::iter::once(self.output_path())
+            .chain(self.output.iter().map(PathBuf::as_path))
+            .collect();
+        output_paths.sort();
+        output_paths.dedup();
+        output_paths
+    }
+
+    /// Pairs of an output path and an input path one of which is inside the other. Inputs are not
+    /// hashed with `--key`, so nothing overlaps then
+    fn overlapping_paths(&self) -> Result<Vec<(&Path, &Path)>> {
+        if self.key.is_some() {
+            return Ok(Vec::new());
+        }
+        let inputs = self
+            .input_paths()
+            .into_iter()
+            .map(|input| Ok((input, absolute_path(input)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let mut overlapping = Vec::new();
+        for output in self.output_paths() {
+            let absolute_output = absolute_path(output)?;
+            for (input, absolute_input) in &inputs {
+                if absolute_output.starts_with(absolute_input)
+                    || absolute_input.starts_with(&absolute_output)
+                {
+                    overlapping.push((output, *input));
+                }
+            }
+        }
+        Ok(overlapping)
+    }
+
+    /// Parses durations such as `500ms`, `30s`, `5m`, `2h` or `7d`, units may be combined as in
+    /// `1h 30m`
+    fn parse_duration(input: &str) -> Result<Duration, String> {
+        humantime::parse_duration(input).map_err(|e| e.to_string())
+    }
+
+    fn non_zero_duration(input: &str) -> Result<Duration, String> {
+        let parsed = Self::parse_duration(input)?;
+        if parsed == Duration::new(0, 0) {
+            Err("Duration cannot be zero".to_string())
+        } else {
+            Ok(parsed)
+        }
+    }
+
+    fn non_zero_count(input: &str) -> Result<usize, &'static str> {
+        match input.parse() {
+            Ok(count) if count > 0 => Ok(count),
+            _ => Err("Value must be a positive integer"),
+        }
+    }
+
+    fn env_assignment(input: &str) -> Result<(String, String), &'static str> {
+        match input.split_once('=') {
+            Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())),
+            _ => Err("Expected NAME=VALUE"),
+        }
+    }
+
+    /// Variables given with `--set-env`, the last one winning when set more than once
+    fn set_env_vars(&self) -> BTreeMap<&str, &str> {
+        self.set_env
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect()
+    }
+
+    /// Value of an environment variable as the command sees it
+    fn command_env_var(&self, name: &str) -> Option<OsString> {
+        match self.set_env_vars().get(name) {
+            Some(value) => Some(OsString::from(value)),
+            None if self.clear_env => None,
+            None => std::env::var_os(name),
+        }
+    }
+
+    fn compression_jobs(&self) -> usize {
+        self.jobs.unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(usize::from)
+                .unwrap_or(1)
+        })
+    }
+
+    /// Whether a failure to write to the cache only deserves a warning. Errors that do not come
+    /// from the filesystem, such as a missing output, are mistakes in the command line.
+    fn tolerates_cache_error(&self, error: &Report) -> bool {
+        !self.strict_cache
+            && error
+                .chain()
+                .any(|cause| cause.downcast_ref::<std::io::Error>().is_some())
+    }
+
+    fn archive_times(&self) -> ArchiveTimes {
+        if self.reproducible {
+            ArchiveTimes::Zeroed
+        } else if self.preserve_mtime {
+            ArchiveTimes::Exact
+        } else {
+            ArchiveTimes::Seconds
+        }
+    }
+
+    fn compression_level(input: &str) -> Result<u32, &'static str> {
+        match input.parse() {
+            Ok(level) if level <= 9 => Ok(level),
+            _ => Err("Compression level must be between 0 and 9"),
+        }
+    }
+
+    fn time_format(input: &str) -> Result<String, &'static str> {
+        if StrftimeItems::new(input).any(|item| matches!(item, Item::Error)) {
+            return Err("Invalid time format");
+        }
+        Ok(input.to_string())
+    }
+
+    /// Configuration of the text logger
+    fn logger_config(&self) -> simplelog::Config {
+        let mut builder = ConfigBuilder::new();
+        match &self.log_time_format {
+            Some(format) => builder
+                .set_time_level(log::LevelFilter::Error)
+                .set_time_to_local(true)
+                .set_time_format(format.clone()),
+            None => builder
+                .set_time_level(log::LevelFilter::Debug)
+                .set_time_format_str("%M:%S.%6f"),
+        };
+        builder.clear_filter_ignore().build()
+    }
+
+    fn compress_threshold(input: &str) -> Result<f64, &'static str> {
+        match input.parse() {
+            Ok(threshold) if threshold > 0.0 && threshold <= 1.0 => Ok(threshold),
+            _ => Err("Compression threshold must be above 0 and at most 1"),
+        }
+    }
+
+    fn include_set(&self) -> Result<Option<GlobSet>> {
+        if self.include.is_empty() {
+            return Ok(None);
+        }
+        let mut builder = GlobSetBuilder::new();
+        for pattern in &self.include {
+            builder.add(
+                GlobBuilder::new(pattern)
+                    .literal_separator(true)
+                    .build()
+                    .wrap_err(format!("Invalid include pattern {}", pattern))?,
+            );
+        }
+        Ok(Some(builder.build()?))
+    }
+
+    /// A spinner rendered with `template`, hidden when not attached to a terminal or with `--quiet`
+    fn progress_bar(&self, template: &str) -> ProgressBar {
+        if self.quiet || !std::io::stdout().is_terminal() {
+            return ProgressBar::hidden();
+        }
+        let progress =
+            ProgressBar::new_spinner().with_style(ProgressStyle::with_template(template).unwrap());
+        progress.enable_steady_tick(Duration::from_millis(100));
+        progress
+    }
+
+    fn remote_backend(&self) -> Result<Option<Box<dyn CacheBackend>>> {
+        #[cfg(feature = "s3")]
+        if let Some(bucket) = &self.s3_bucket {
+            return S3Backend::connect(bucket, &self.s3_prefix)
+                .map(|backend| Some(Box::new(backend) as Box<dyn CacheBackend>));
+        }
+        #[cfg(not(feature = "s3"))]
+        if self.s3_bucket.is_some() {
+            return Err(eyre!(
+                "folca was built without S3 support, enable the `s3` feature"
+            ));
+        }
+        Ok(self
+            .remote_url
+            .as_deref()
+            .map(|url| Box::new(HttpBackend::new(url)) as Box<dyn CacheBackend>))
+    }
+
+    fn command_input_key(&self) -> Result<CommandInputHashes> {
+        match self.hash_algo {
+            HashAlgo::Fnv1a => self.command_input_key_with(Fnv1aHasher::default),
+            HashAlgo::Blake3 => self.command_input_key_with(blake3::Hasher::new),
+        }
+    }
+
+    /// Computes the key with fresh hashers from `new_hasher` for the command, the inputs and
+    /// each input file. The walk does not depend on the hash function in any other way.
+    fn command_input_key_with<H: ContentHasher>(
+        &self,
+        new_hasher: fn() -> H,
+    ) -> Result<CommandInputHashes> {
+        let command_hash = self.command_hasher(new_hasher)?.finish();
+        let progress = self.progress_bar(HASHING_PROGRESS);
+        let input_hash = self
+            .input_hasher(new_hasher, self.hash_cache, command_hash, &progress)?
+            .finish();
+        Ok(CommandInputHashes {
+            command_hash,
+            input_hash,
+        })
+    }
+
+    /// Second digest of the command and inputs for `--collision-guard`. File hashes are never
+    /// cached for it, as the hash cache only holds 64-bit ones.
+    fn collision_guard(&self) -> Result<blake3::Hash> {
+        let command = self.command_hasher(GuardHasher::default)?;
+        let progress = self.progress_bar(HASHING_PROGRESS);
+        let inputs = self.input_hasher(
+            GuardHasher::default,
+            false,
+            command.clone().finish(),
+            &progress,
+        )?;
+        let mut guard = GuardHasher::default();
+        guard.write(command.digest().as_bytes());
+        guard.write(inputs.digest().as_bytes());
+        Ok(guard.digest())
+    }
+
+    /// Hasher fed with the command line, along with the environment and working directory if
+    /// asked to
+    fn command_hasher<H: ContentHasher>(&self, new_hasher: fn() -> H) -> Result<H> {
+        let mut command_hasher = new_hasher();
+        if let Some(namespace) = &self.namespace {
+            command_hasher.write(b"namespace");
+            command_hasher.write(&(namespace.len() as u64).to_be_bytes());
+            command_hasher.write(namespace.as_bytes());
+        }
+        if !self.ignore_command {
+            for command_part in &self.command {
+                command_hasher.write(command_part.as_bytes());
+            }
+        }
+        if self.hash_cwd {
+            let cwd = std::env::current_dir()?;
+            command_hasher.write(&self.path_normalization.path_bytes(&cwd));
+        }
+        // Variables set for the command are as much part of it as its arguments
+        if self.clear_env {
+            command_hasher.write(b"clear-env");
+        }
+        // The same words mean something else to the shell
+        if self.shell && !self.ignore_command {
+            command_hasher.write(b"shell");
+        }
+        for (name, value) in self.set_env_vars() {
+            command_hasher.write(name.as_bytes());
+            command_hasher.write(&(value.len() as u64).to_be_bytes());
+            command_hasher.write(value.as_bytes());
+        }
+        let env_keys: BTreeSet<&String> = self.env_key.iter().collect();
+        for name in env_keys {
+            command_hasher.write(name.as_bytes());
+            match self.command_env_var(name) {
+                None => command_hasher.write(&[0]),
+                Some(value) => {
+                    let value = value.to_string_lossy();
+                    command_hasher.write(&[1]);
+                    command_hasher.write(&(value.len() as u64).to_be_bytes());
+                    command_hasher.write(value.as_bytes());
+                }
+            }
+        }
+        Ok(command_hasher)
+    }
+
+    /// Hasher fed with the contents of the inputs, or with `--key`. Input file hashes are only
+    /// remembered under `command_hash` with `hash_cache`, as they depend on the function.
+    /// `progress` is advanced once per hashed file and cleared at the end.
+    fn input_hasher<H: ContentHasher>(
+        &self,
+        new_hasher: fn() -> H,
+        hash_cache: bool,
+        command_hash: u64,
+        progress: &ProgressBar,
+    ) -> Result<H> {
+        let mut hasher = new_hasher();
+        if let Some(namespace) = &self.namespace {
+            hasher.write(b"namespace");
+            hasher.write(&(namespace.len() as u64).to_be_bytes());
+            hasher.write(namespace.as_bytes());
+        }
+        if let Some(key) = &self.key {
+            trace!("Using the given key instead of hashing inputs");
+            hasher.write(key.as_bytes());
+            return Ok(hasher);
+        }
+
+        // Sampled file hashes differ from full ones, keep them from sharing entries
+        if let Some(sample_bytes) = self.sample_bytes {
+            hasher.write(b"sample");
+            hasher.write(&sample_bytes.to_be_bytes());
+        }
+        if let Some(max_file_size) = self.max_file_size {
+            hasher.write(b"max-file-size");
+            hasher.write(&max_file_size.to_be_bytes());
+        }
+        if self.dry_run {
+            trace!("initial hash state: {:x}", hasher.clone().finish());
+        }
+
+        // Inputs are walked in sorted order so the key does not depend on how they were passed
+        let mut input_paths = self.input_paths();
+        if let Some(missing) = input_paths
+            .iter()
+            .find(|path| path.symlink_metadata().is_err())
+        {
+            if !self.allow_empty_input {
+                return Err(eyre!(
+                    "Input path {} does not exist, pass --allow-empty-input to cache anyway",
+                    missing.to_string_lossy()
+                ));
+            }
+        }
+        input_paths.retain(|path| path.symlink_metadata().is_ok());
+        if input_paths.is_empty() {
+            trace!("None of the input paths exist");
+            return Ok(hasher);
+        }
+        let mut walk = WalkBuilder::new(input_paths[0]);
+        for input_path in &input_paths[1..] {
+            walk.add(input_path);
+        }
+        // Unlike `.gitignore`, these are meant for folca alone and so are always respected
+        walk.add_custom_ignore_filename(IGNORE_FILE_NAME);
+
+        // Globs are matched relative to the first input, `!` turns an override into an ignore rule
+        let mut overrides = OverrideBuilder::new(input_paths[0]);
+        for pattern in &self.exclude {
+            overrides
+                .add(&format!("!{}", pattern))
+                .wrap_err(format!("Invalid exclude pattern {}", pattern))?;
+        }
+        let includes = self.include_set()?;
+        let mut file_hashes = if hash_cache {
+            Some(FileHashCache::load(
+                &self.cache_path,
+                self.hash_algo,
+                self.sample_bytes,
+            ))
+        } else {
+            None
+        };
+
+        let mut root = PathBuf::new();
+        let mut root_index = 0u64;
+        let mut hashed_entries = 0usize;
+        // The walk only gathers what to hash, file contents are then hashed in parallel and
+        // everything folded into the key in walk order
+        let mut entries: Vec<InputEntry> = Vec::new();
+        for entry in walk
+            .overrides(overrides.build()?)
+            .hidden(!self.include_hidden)
+            .ignore(self.respect_ignore)
+            .git_ignore(self.respect_ignore)
+            .git_global(self.respect_ignore)
+            .git_exclude(self.respect_ignore)
+            .require_git(false)
+            .follow_links(self.follow_symlinks)
+            .sort_by_file_path(|p1, p2| p1.cmp(p2))
+            .skip_stdout(true)
+            .build()
+        {
+            let dir_entry = match entry {
+                Ok(dir_entry) => dir_entry,
+                Err(e) if is_symlink_loop(&e) => {
+                    warn!("Skipping symlink loop: {}", e);
+                    continue;
+                }
+                // Followed links that point nowhere are hashed by target, as when not following
+                Err(e) => match dangling_link(&e).filter(|link| link.starts_with(&root)) {
+                    Some(link) => {
+                        let relative = link.strip_prefix(&root).unwrap_or(link);
+                        if let Some(includes) = &includes {
+                            if !includes.is_match(relative) {
+                                continue;
+                            }
+                        }
+                        trace!("{} is a dangling symlink", link.to_string_lossy());
+                        hashed_entries += 1;
+                        let target = std::fs::read_link(link)?;
+                        entries.push(InputEntry {
+                            path: link.to_owned(),
+                            name: self.path_normalization.path_bytes(relative).into_owned(),
+                            content: InputContent::Link(
+                                self.path_normalization.path_bytes(&target).into_owned(),
+                            ),
+                        });
+                        continue;
+                    }
+                    None => {
+                        warn!("{}", e);
+                        return Err(e.into());
+                    }
+                },
+            };
+            let path = dir_entry.path();
+            if dir_entry.depth() == 0 {
+                root = path.to_path_buf();
+                root_index += 1;
+            }
+            let relative = path.strip_prefix(&root).unwrap_or(path);
+            // Only matching files count when includes are given, paths passed explicitly always do
+            if let Some(includes) = &includes {
+                if dir_entry.depth() > 0 && (path.is_dir() || !includes.is_match(relative)) {
+                    continue;
+                }
+            }
+            // Paths are hashed relative to their input so the key does not depend on its location,
+            // inputs themselves are only told apart by position
+            let name = if dir_entry.depth() == 0 {
+                root_index.to_be_bytes().to_vec()
+            } else {
+                self.path_normalization.path_bytes(relative).into_owned()
+            };
+            let mut input_entry = InputEntry {
+                path: path.to_owned(),
+                name,
+                content: InputContent::Special,
+            };
+
+            // Inputs are always followed, links below them are hashed by target unless asked to
+            if dir_entry.depth() > 0 && dir_entry.path_is_symlink() && !self.follow_symlinks {
+                hashed_entries += 1;
+                match std::fs::read_link(path) {
+                    Ok(target) => {
+                        input_entry.content = InputContent::Link(
+                            self.path_normalization.path_bytes(&target).into_owned(),
+                        )
+                    }
+                    Err(e) => warn!("{}", e),
+                }
+            } else if path.is_file() {
+                hashed_entries += 1;
+                let metadata = if self.hash_mode || self.max_file_size.is_some() {
+                    path.metadata().map_err(|e| warn!("{}", e)).ok()
+                } else {
+                    None
+                };
+                let mode = metadata.as_ref().filter(|_| self.hash_mode).map(file_mode);
+                input_entry.content = match (&metadata, self.max_file_size) {
+                    (Some(metadata), Some(max_file_size)) if metadata.len() > max_file_size => {
+                        trace!(
+                            "{} is larger than --max-file-size, only hashing its metadata",
+                            path.to_string_lossy()
+                        );
+                        let mtime = metadata
+                            .modified()
+                            .ok()
+                            .and_then(|mtime| mtime.duration_since(SystemTime::UNIX_EPOCH).ok())
+                            .map_or(0, |mtime| mtime.as_nanos() as u64);
+                        InputContent::Metadata {
+                            size: metadata.len(),
+                            mtime,
+                            mode,
+                        }
+                    }
+                    _ => InputContent::File { hash: None, mode },
+                };
+            } else if path.is_dir() {
+                input_entry.content = InputContent::Dir;
+            } else {
+                if self.skip_special {
+                    trace!(
+                        "{} is not a file or a directory, skipping it",
+                        path.to_string_lossy()
+                    );
+                    continue;
+                }
+                warn!(
+                    "{} is not a file or a directory, only hashing its path",
+                    path.to_string_lossy()
+                );
+            }
+            entries.push(input_entry);
+        }
+
+        // Contents are hashed per file and folded in, so cached file hashes give the same key
+        let hashed: Vec<_> = entries
+            .par_iter()
+            .enumerate()
+            .filter(|(_, entry)| matches!(entry.content, InputContent::File { .. }))
+            .map_init(
+                || vec![0u8; self.read_buffer_size as usize],
+                |buffer, (index, entry)| {
+                    let result = match &file_hashes {
+                        Some(file_hashes) => file_hashes.hash(new_hasher(), buffer, &entry.path),
+                        None => {
+                            Config::hash_file(new_hasher(), buffer, &entry.path, self.sample_bytes)
+                                .map(|hash| (hash, None))
+                        }
+                    };
+                    progress.inc(1);
+                    (index, result)
+                },
+            )
+            .collect();
+        for (index, result) in hashed {
+            let entry = &mut entries[index];
+            match result {
+                Ok((file_hash, remembered)) => {
+                    if let Some(file_hashes) = &mut file_hashes {
+                        file_hashes.remember(&entry.path, remembered);
+                    }
+                    if let InputContent::File { hash, .. } = &mut entry.content {
+                        *hash = Some(file_hash);
+                    }
+                }
+                Err(e) => warn!("{}", e),
+            }
+        }
+
+        for entry in &entries {
+            hasher.write(&entry.name);
+            if self.dry_run {
+                trace!(
+                    "after hashing the path {}: {:x}",
+                    entry.path.to_string_lossy(),
+                    hasher.clone().finish()
+                );
+            }
+            // Tagged so that each kind of entry gives its own key, whatever its payload
+            hasher.write(&[entry.content.tag()]);
+            match &entry.content {
+                InputContent::Special | InputContent::Dir => {}
+                InputContent::Link(target) => hasher.write(target),
+                InputContent::File { hash, mode } => {
+                    if let Some(hash) = hash {
+                        hash.write_to(&mut hasher);
+                    }
+                    if let Some(mode) = mode {
+                        hasher.write(&mode.to_be_bytes());
+                    }
+                }
+                InputContent::Metadata { size, mtime, mode } => {
+                    hasher.write(&size.to_be_bytes());
+                    hasher.write(&mtime.to_be_bytes());
+                    if let Some(mode) = mode {
+                        hasher.write(&mode.to_be_bytes());
+                    }
+                }
+            }
+        }
+
+        progress.finish_and_clear();
+        if hashed_entries == 0 && !self.allow_empty_input {
+            return Err(eyre!(
+                "No input files found in {}, pass --allow-empty-input to cache anyway",
+                input_paths
+                    .iter()
+                    .map(|path| path.to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+
+        if let Some(file_hashes) = file_hashes {
+            if self.dry_run {
+                file_hashes.log_changes(command_hash);
+            }
+            if !self.dry_run && !self.read_only && !self.print_key {
+                file_hashes
+                    .save(&input_paths)
+                    .unwrap_or_else(|e| warn!("Cannot save file hashes: {}", e));
+            }
+        }
+
+        Ok(hasher)
+    }
+
+    /// Hashes the content of a file, or with `sample_bytes` only its size and both ends when it
+    /// is larger than them
+    fn hash_file<H: ContentHasher>(
+        mut hasher: H,
+        buffer: &mut [u8],
+        path: &Path,
+        sample_bytes: Option<u64>,
+    ) -> Result<FileDigest> {
+        trace!("Hashing content of {}", path.to_string_lossy());
+        // Opening a fifo blocks until it has a writer, it may have replaced the file since the walk
+        if !path.metadata()?.is_file() {
+            return Err(eyre!("{} is no longer a file", path.to_string_lossy()));
+        }
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+        match sample_bytes {
+            Some(sample_bytes) if size > sample_bytes.saturating_mul(2) => {
+                hasher.write(&size.to_be_bytes());
+                Self::hash_reader(&mut hasher, buffer, (&mut file).take(sample_bytes))?;
+                file.seek(SeekFrom::Start(size - sample_bytes))?;
+                Self::hash_reader(&mut hasher, buffer, file.take(sample_bytes))?;
+            }
+            _ => Self::hash_reader(&mut hasher, buffer, file)?,
+        }
+
+        trace!("Hashed content of {}", path.to_string_lossy());
+        Ok(hasher.file_digest())
+    }
+
+    fn hash_reader<H: ContentHasher>(
+        hasher: &mut H,
+        buffer: &mut [u8],
+        mut reader: impl Read,
+    ) -> Result<()> {
+        loop {
+            let bytes_read = reader.read(buffer)?;
+            if bytes_read == 0 {
+                return Ok(());
+            }
+            hasher.write(&buffer[0..bytes_read]);
+        }
+    }
+}
+
+const IGNORE_FILE_NAME: &str = ".folcaignore";
+
+/// Hash of an input file, as folded into the key
+#[derive(Clone, Copy)]
+enum FileDigest {
+    /// Truncated to 64 bits, which are what `--hash-cache` remembers
+    Short(u64),
+    /// Whole blake3 digest, for `--collision-guard`
+    Full(blake3::Hash),
+}
+
+impl FileDigest {
+    fn write_to<H: ContentHasher>(&self, hasher: &mut H) {
+        match self {
+            FileDigest::Short(hash) => hasher.write(&hash.to_be_bytes()),
+            FileDigest::Full(hash) => hasher.write(hash.as_bytes()),
+        }
+    }
+}
+
+/// Something found while walking the inputs, to be folded into the key
+struct InputEntry {
+    path: PathBuf,
+    /// Position of the input for the inputs themselves, their relative path below them
+    name: Vec<u8>,
+    content: InputContent,
+}
+
+enum InputContent {
+    /// Special files only contribute their name and kind
+    Special,
+    /// Directories only contribute their name and kind too, so that empty ones change the key
+    Dir,
+    /// Target of a symlink that is not followed
+    Link(Vec<u8>),
+    /// Content hash, filled in once hashed, and permission bits with `--hash-mode`
+    File {
+        hash: Option<FileDigest>,
+        mode: Option<u32>,
+    },
+    /// Size and modification time in nanoseconds of files above `--max-file-size`
+    Metadata {
+        size: u64,
+        mtime: u64,
+        mode: Option<u32>,
+    },
+}
+
+impl InputContent {
+    fn tag(&self) -> u8 {
+        match self {
+            InputContent::Special => b's',
+            InputContent::Dir => b'd',
+            InputContent::Link(_) => b'l',
+            InputContent::File { .. } => b'f',
+            InputContent::Metadata { .. } => b'm',
+        }
+    }
+}
+
+const METADATA_EXTENSION: &str = "json";
+const INDEX_FILE: &str = "index.json";
+const BLOBS_DIR: &str = "blobs";
+const LOCKS_DIR: &str = ".locks";
+const INDEX_LOCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Every entry of the cache along with its metadata, stored at the root of the cache
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheIndex {
+    entries: Vec<IndexEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct IndexEntry {
+    command_hash: u64,
+    input_hash: u64,
+    compression: CompressionAlgo,
+    size: u64,
+    /// Time of creation or of the latest cache hit, used for LRU eviction
+    last_used: SystemTime,
+    /// blake3 digest of the archive, checked before restoring
+    checksum: Option<String>,
+    /// blake3 digest of the outputs, checked after restoring with `--verify-restore`
+    #[serde(default)]
+    output_digest: Option<String>,
+    /// Set for failed runs cached with `--cache-failures`, which are misses without it
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    failed: bool,
+    /// blake3 digest of the zstd dictionary the archive was compressed with, see `--dict`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dictionary: Option<String>,
+    /// Second digest of the command and inputs, see `--collision-guard`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    collision_guard: Option<String>,
+}
+
+impl From<(&CommandInputHashes, &LastUsedAndSize)> for IndexEntry {
+    fn from((key, value): (&CommandInputHashes, &LastUsedAndSize)) -> Self {
+        Self {
+            command_hash: key.command_hash,
+            input_hash: key.input_hash,
+            compression: value.compression,
+            size: value.size,
+            last_used: value.last_used,
+            checksum: value.checksum.map(|checksum| checksum.to_hex().to_string()),
+            output_digest: value
+                .output_digest
+                .map(|digest| digest.to_hex().to_string()),
+            failed: value.failed,
+            dictionary: value
+                .dictionary
+                .map(|dictionary| dictionary.to_hex().to_string()),
+            collision_guard: value.guard.map(|guard| guard.to_hex().to_string()),
+        }
+    }
+}
+
+impl IndexEntry {
+    fn into_entry(self) -> Result<(CommandInputHashes, LastUsedAndSize)> {
+        let checksum = match self.checksum {
+            Some(checksum) => Some(blake3::Hash::from_hex(checksum)?),
+            None => None,
+        };
+        let output_digest = match self.output_digest {
+            Some(digest) => Some(blake3::Hash::from_hex(digest)?),
+            None => None,
+        };
+        let dictionary = match self.dictionary {
+            Some(dictionary) => Some(blake3::Hash::from_hex(dictionary)?),
+            None => None,
+        };
+        let guard = match self.collision_guard {
+            Some(guard) => Some(blake3::Hash::from_hex(guard)?),
+            None => None,
+        };
+        Ok((
+            CommandInputHashes {
+                command_hash: self.command_hash,
+                input_hash: self.input_hash,
+            },
+            LastUsedAndSize {
+                last_used: self.last_used,
+                size: self.size,
+                compression: self.compression,
+                checksum,
+                output_digest,
+                failed: self.failed,
+                dictionary,
+                guard,
+            },
+        ))
+    }
+}
+
+/// Sidecar stored next to each cached archive before the index existed, read when rebuilding it
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct EntryMetadata {
+    /// blake3 digest of the archive, checked before restoring
+    checksum: Option<String>,
+    /// Time of creation or of the latest cache hit, used for LRU eviction
+    last_used: Option<SystemTime>,
+}
+
+/// Content hashes of input files from previous runs, stored at the root of the cache
+#[derive(Debug, Serialize, Deserialize)]
+struct FileHashCache {
+    hash_algo: HashAlgo,
+    files: HashMap<PathBuf, FileHashEntry>,
+    /// Hashes of the input files of the latest cached run of each command, by command hash
+    #[serde(default)]
+    last_runs: HashMap<String, BTreeMap<PathBuf, u64>>,
+    /// `--sample-bytes` the hashes were computed with
+    #[serde(default)]
+    sample_bytes: Option<u64>,
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(skip)]
+    seen: HashSet<PathBuf>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct FileHashEntry {
+    modified: SystemTime,
+    size: u64,
+    hash: u64,
+    /// When the content was read, files modified shortly before may have changed unnoticed
+    hashed_at: SystemTime,
+}
+
+impl FileHashCache {
+    const FILE_NAME: &'static str = ".file_hashes.json";
+    /// Coarsest modification time resolution of common filesystems
+    const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+    fn load(cache_path: &Path, hash_algo: HashAlgo, sample_bytes: Option<u64>) -> Self {
+        let path = cache_path.join(Self::FILE_NAME);
+        let stored = std::fs::read(&path)
+            .map_err(Report::from)
+            .and_then(|contents| Ok(serde_json::from_slice::<Self>(&contents)?));
+        let (files, last_runs) = match stored {
+            Ok(stored) if stored.hash_algo == hash_algo && stored.sample_bytes == sample_bytes => {
+                (stored.files, stored.last_runs)
+            }
+            Ok(_) => {
+                trace!("File hashes were computed with other options, discarding them");
+                Default::default()
+            }
+            Err(e) => {
+                trace!("No file hashes loaded: {}", e);
+                Default::default()
+            }
+        };
+        Self {
+            hash_algo,
+            files,
+            last_runs,
+            sample_bytes,
+            path,
+            seen: HashSet::new(),
+        }
+    }
+
+    /// Hashes a file unless its remembered hash is still valid, along with what to remember when
+    /// it was read again
+    fn hash<H: ContentHasher>(
+        &self,
+        hasher: H,
+        buffer: &mut [u8],
+        path: &Path,
+    ) -> Result<(FileDigest, Option<FileHashEntry>)> {
+        let metadata = std::fs::metadata(path)?;
+        let modified = metadata.modified()?;
+        let size = metadata.len();
+
+        if let Some(entry) = self.files.get(path) {
+            if entry.modified == modified
+                && entry.size == size
+                && entry.hashed_at >= modified + Self::RACY_WINDOW
+            {
+                trace!("Reusing hash of {}", path.to_string_lossy());
+                return Ok((FileDigest::Short(entry.hash), None));
+            }
+        }
+
+        let hashed_at = SystemTime::now();
+        let digest = Config::hash_file(hasher, buffer, path, self.sample_bytes)?;
+        // Whole digests do not fit, they are hashed again on every run
+        let remembered = match digest {
+            FileDigest::Short(hash) => Some(FileHashEntry {
+                modified,
+                size,
+                hash,
+                hashed_at,
+            }),
+            FileDigest::Full(_) => None,
+        };
+        Ok((digest, remembered))
+    }
+
+    /// Marks a file as still under the inputs, storing its new hash if it was read again
+    fn remember(&mut self, path: &Path, entry: Option<FileHashEntry>) {
+        self.seen.insert(path.to_owned());
+        if let Some(entry) = entry {
+            self.files.insert(path.to_owned(), entry);
+        }
+    }
+
+    /// Logs which input files were added, removed or changed since the latest cached run of the
+    /// command, to tell why its key changed
+    fn log_changes(&self, command_hash: u64) {
+        let last_run = match self.last_runs.get(&format!("{:x}", command_hash)) {
+            Some(last_run) => last_run,
+            None => {
+                info!("No cached run of this command to compare the inputs with");
+                return;
+            }
+        };
+        let current: BTreeMap<&Path, u64> = self
+            .seen
+            .iter()
+            .filter_map(|path| Some((path.as_path(), self.files.get(path)?.hash)))
+            .collect();
+
+        let mut changes = 0usize;
+        for (path, hash) in &current {
+            match last_run.get(*path) {
+                None => info!(
+                    "Added since the last cached run: {}",
+                    path.to_string_lossy()
+                ),
+                Some(last_hash) if last_hash != hash => {
+                    info!(
+                        "Changed since the last cached run: {}",
+                        path.to_string_lossy()
+                    )
+                }
+                Some(_) => continue,
+            }
+            changes += 1;
+        }
+        for path in last_run.keys() {
+            if !current.contains_key(path.as_path()) {
+                info!(
+                    "Removed since the last cached run: {}",
+                    path.to_string_lossy()
+                );
+                changes += 1;
+            }
+        }
+        if changes == 0 {
+            info!("No input file changed since the last cached run of this command");
+        }
+    }
+
+    /// Remembers the hashes of the input files of a run that was just cached, which are those
+    /// left under the inputs by the latest save
+    fn record_run(config: &Config, command_hash: u64) -> Result<()> {
+        let input_paths = config.input_paths();
+        let mut file_hashes = Self::load(&config.cache_path, config.hash_algo, config.sample_bytes);
+        let inputs = file_hashes
+            .files
+            .iter()
+            .filter(|(path, _)| input_paths.iter().any(|input| path.starts_with(input)))
+            .map(|(path, entry)| (path.clone(), entry.hash))
+            .collect();
+        file_hashes
+            .last_runs
+            .insert(format!("{:x}", command_hash), inputs);
+        file_hashes.write()
+    }
+
+    /// Writes the hashes back, dropping files that were not found under the inputs
+    fn save(mut self, input_paths: &[&Path]) -> Result<()> {
+        let seen = &self.seen;
+        self.files.retain(|path, _| {
+            seen.contains(path) || !input_paths.iter().any(|input| path.starts_with(input))
+        });
+        self.write()
+    }
+
+    fn write(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let temp_path = temp_path(&self.path);
+        std::fs::write(&temp_path, serde_json::to_vec(self)?)?;
+        std::fs::rename(&temp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Storage for cache entries, addressed by their name relative to the cache root
+trait CacheBackend: std::fmt::Debug {
+    /// Opens an entry, `None` if there is no such entry
+    fn get(&self, name: &str) -> Result<Option<Box<dyn Read>>>;
+    fn put(&self, name: &str, data: &mut dyn Read) -> Result<()>;
+    /// Names of all entries
+    fn list(&self) -> Result<Vec<String>>;
+    /// Removes an entry, failing if there is no such entry
+    fn remove(&self, name: &str) -> Result<()>;
+}
+
+/// Entries stored as files under a local directory
+#[derive(Debug)]
+struct FilesystemBackend {
+    root: PathBuf,
+}
+
+impl FilesystemBackend {
+    /// `list`, calling `read_dir` with every directory it reads, each once
+    fn list_reading(&self, mut read_dir: impl FnMut(&Path)) -> Result<Vec<String>> {
+        Ok(WalkDir::new(&self.root)
+            .max_depth(CacheLayout::MAX_DEPTH)
+            .into_iter()
+            .filter_map(|e| match e {
+                Err(err) => {
+                    warn!("{}", err);
+                    None
+                }
+                Ok(walkdir_entry) if walkdir_entry.file_type().is_dir() => {
+                    // Directories as deep as the walk goes are not read
+                    if walkdir_entry.depth() < CacheLayout::MAX_DEPTH {
+                        read_dir(walkdir_entry.path());
+                    }
+                    None
+                }
+                Ok(walkdir_entry) if walkdir_entry.depth() < CacheLayout::MIN_DEPTH => None,
+                Ok(walkdir_entry) => Some(walkdir_entry.path().to_owned()),
+            })
+            .filter(|path| !path.starts_with(self.root.join(BLOBS_DIR)))
+            .filter(|path| path.extension() != Some(METADATA_EXTENSION.as_ref()))
+            .filter(|path| {
+                let is_temp = is_temp_path(path);
+                if is_temp {
+                    trace!("Skipping unfinished write {}", path.to_string_lossy());
+                }
+                !is_temp
+            })
+            .filter_map(|path| {
+                path.strip_prefix(&self.root)
+                    .ok()
+                    .map(|name| name.to_string_lossy().replace('\\', "/"))
+            })
+            .collect())
+    }
+}
+
+impl CacheBackend for FilesystemBackend {
+    fn get(&self, name: &str) -> Result<Option<Box<dyn Read>>> {
+        match File::open(self.root.join(name)) {
+            Ok(file) => Ok(Some(Box::new(file))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn put(&self, name: &str, data: &mut dyn Read) -> Result<()> {
+        let path = self.root.join(name);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let temp_path = temp_path(&path);
+        let written = File::create(&temp_path)
+            .and_then(|mut file| std::io::copy(data, &mut file))
+            .and_then(|_| std::fs::rename(&temp_path, &path));
+        if let Err(e) = written {
+            std::fs::remove_file(&temp_path).unwrap_or_else(|e| warn!("{}", e));
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// Entries are the files as deep as some layout puts archives, blobs, metadata sidecars and
+    /// unfinished writes are skipped
+    fn list(&self) -> Result<Vec<String>> {
+        self.list_reading(|_| {})
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        let path = self.root.join(name);
+        remove_file_and_empty_dirs(&self.root, &path)
+            .wrap_err(format!("Folca: cannot remove {}", &path.to_string_lossy()))
+    }
+}
+
+/// Entries served over HTTP: `GET`, `PUT` and `DELETE` on `<url>/<name>`, and `GET <url>/` listing
+/// one name per line
+#[derive(Debug)]
+struct HttpBackend {
+    url: String,
+    agent: ureq::Agent,
+}
+
+impl HttpBackend {
+    fn new(url: &str) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(Duration::from_secs(10))
+                .build(),
+        }
+    }
+
+    fn entry_url(&self, name: &str) -> String {
+        format!("{}/{}", self.url, name)
+    }
+}
+
+impl CacheBackend for HttpBackend {
+    fn get(&self, name: &str) -> Result<Option<Box<dyn Read>>> {
+        match self.agent.get(&self.entry_url(name)).call() {
+            Ok(response) => Ok(Some(Box::new(response.into_reader()))),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn put(&self, name: &str, data: &mut dyn Read) -> Result<()> {
+        self.agent.put(&self.entry_url(name)).send(data)?;
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let listing = self
+            .agent
+            .get(&format!("{}/", self.url))
+            .call()?
+            .into_string()?;
+        Ok(listing
+            .lines()
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(String::from)
+            .collect())
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        self.agent.delete(&self.entry_url(name)).call()?;
+        Ok(())
+    }
+}
+
+/// Entries stored as objects of an S3 bucket, named like local files under an optional prefix
+#[cfg(feature = "s3")]
+#[derive(Debug)]
+struct S3Backend {
+    client: aws_sdk_s3::Client,
+    bucket: String,
+    prefix: String,
+    runtime: tokio::runtime::Runtime,
+}
+
+#[cfg(feature = "s3")]
+impl S3Backend {
+    fn connect(bucket: &str, prefix: &str) -> Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let config = runtime.block_on(aws_config::load_defaults(
+            aws_config::BehaviorVersion::latest(),
+        ));
+        // Self-hosted S3-compatible servers set through `AWS_ENDPOINT_URL` rarely support
+        // virtual-hosted buckets
+        let s3_config = aws_sdk_s3::config::Builder::from(&config)
+            .force_path_style(std::env::var_os("AWS_ENDPOINT_URL").is_some())
+            .build();
+        Ok(Self {
+            client: aws_sdk_s3::Client::from_conf(s3_config),
+            bucket: bucket.to_string(),
+            prefix: prefix.trim_matches('/').to_string(),
+            runtime,
+        })
+    }
+
+    fn object_key(&self, name: &str) -> String {
+        if self.prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}/{}", self.prefix, name)
+        }
+    }
+}
+
+#[cfg(feature = "s3")]
+impl CacheBackend for S3Backend {
+    fn get(&self, name: &str) -> Result<Option<Box<dyn Read>>> {
+        let request = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(self.object_key(name));
+        let object = match self.runtime.block_on(request.send()) {
+            Ok(object) => object,
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let body = self.runtime.block_on(object.body.collect())?;
+        Ok(Some(Box::new(std::io::Cursor::new(body.into_bytes()))))
+    }
+
+    fn put(&self, name: &str, data: &mut dyn Read) -> Result<()> {
+        let mut body = Vec::new();
+        data.read_to_end(&mut body)?;
+        let request = self
+            .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(self.object_key(name))
+            .body(body.into());
+        self.runtime.block_on(request.send())?;
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let prefix = self.object_key("");
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(&prefix)
+            .into_paginator()
+            .send();
+        let mut names = Vec::new();
+        while let Some(page) = self.runtime.block_on(pages.next()) {
+            names.extend(
+                page?
+                    .contents()
+                    .iter()
+                    .filter_map(|object| object.key())
+                    .filter_map(|key| key.strip_prefix(&prefix))
+                    .map(String::from),
+            );
+        }
+        Ok(names)
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        let request = self
+            .client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(self.object_key(name));
+        self.runtime.block_on(request.send())?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    const VARIANTS: &'static [&'static str] = &["text", "json"];
+}
+
+impl FromStr for LogFormat {
+    type Err = &'static str;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("Unknown log format"),
+        }
+    }
+}
+
+impl std::fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        })
+    }
+}
+
+/// When to color log lines, see `--color`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ColorWhen {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorWhen {
+    const VARIANTS: &'static [&'static str] = &["auto", "always", "never"];
+
+    fn choice(self) -> ColorChoice {
+        match self {
+            ColorWhen::Auto => ColorChoice::Auto,
+            ColorWhen::Always => ColorChoice::Always,
+            ColorWhen::Never => ColorChoice::Never,
+        }
+    }
+}
+
+impl FromStr for ColorWhen {
+    type Err = &'static str;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "auto" => Ok(ColorWhen::Auto),
+            "always" => Ok(ColorWhen::Always),
+            "never" => Ok(ColorWhen::Never),
+            _ => Err("Unknown color choice"),
+        }
+    }
+}
+
+impl std::fmt::Display for ColorWhen {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            ColorWhen::Auto => "auto",
+            ColorWhen::Always => "always",
+            ColorWhen::Never => "never",
+        })
+    }
+}
+
+/// Order of the entries printed by `folca list`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ListSort {
+    Size,
+    Age,
+}
+
+impl ListSort {
+    const VARIANTS: &'static [&'static str] = &["size", "age"];
+}
+
+impl FromStr for ListSort {
+    type Err = &'static str;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "size" => Ok(ListSort::Size),
+            "age" => Ok(ListSort::Age),
+            _ => Err("Unknown sort order"),
+        }
+    }
+}
+
+/// Logs one JSON object per line on stderr, with the record's key-values as extra fields
+struct JsonLogger {
+    level: log::LevelFilter,
+}
+
+impl JsonLogger {
+    fn init(level: log::LevelFilter) -> Result<()> {
+        log::set_boxed_logger(Box::new(Self { level }))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+}
+
+impl log::Log for JsonLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut line = serde_json::Map::new();
+        line.insert(
+            "timestamp".to_string(),
+            humantime::format_rfc3339_micros(SystemTime::now())
+                .to_string()
+                .into(),
+        );
+        line.insert("level".to_string(), record.level().as_str().into());
+        line.insert("target".to_string(), record.target().into());
+        line.insert("message".to_string(), record.args().to_string().into());
+        let mut fields = JsonFields(&mut line);
+        if let Err(e) = record.key_values().visit(&mut fields) {
+            line.insert("fields_error".to_string(), e.to_string().into());
+        }
+
+        let mut stderr = std::io::stderr().lock();
+        // Logging has nowhere to report its own failures
+        let _ = serde_json::to_writer(&mut stderr, &line);
+        let _ = stderr.write_all(b"\n");
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+struct JsonFields<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl<'kvs> log::kv::VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(
+        &mut self,
+        key: log::kv::Key<'kvs>,
+        value: log::kv::Value<'kvs>,
+    ) -> Result<(), log::kv::Error> {
+        let value = if let Some(value) = value.to_bool() {
+            value.into()
+        } else if let Some(value) = value.to_u64() {
+            value.into()
+        } else if let Some(value) = value.to_i64() {
+            value.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// Summary of a run written with `--report`
+#[derive(Debug, Default, Serialize)]
+struct RunReport {
+    hit: bool,
+    command_hash: Option<String>,
+    input_hash: Option<String>,
+    /// Size of the archive restored or written
+    archive_size: Option<u64>,
+    /// Exit code of the command, or the cached one when it was captured
+    exit_code: Option<i32>,
+}
+
+impl RunReport {
+    fn save(&self, path: Option<&Path>) {
+        if let Some(path) = path {
+            let written = serde_json::to_vec_pretty(self)
+                .map_err(Report::from)
+                .and_then(|report| Ok(std::fs::write(path, report)?));
+            if let Err(e) = written {
+                warn!("Cannot write report to {}: {}", path.to_string_lossy(), e);
+            }
+        }
+    }
+}
+
+/// Advisory lock on the cache directory, released on drop
+struct CacheLock(File);
+
+impl CacheLock {
+    fn acquire(path: &Path, exclusive: bool, writable: bool, timeout: Duration) -> Result<Self> {
+        let opened = std::fs::OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .create(writable)
+            .truncate(false)
+            .open(path);
+        // Readers of a cache they cannot write to, e.g. on a read-only mount, lock it all the same
+        let file = match opened {
+            Err(e)
+                if writable
+                    && !exclusive
+                    && matches!(
+                        e.kind(),
+                        std::io::ErrorKind::PermissionDenied
+                            | std::io::ErrorKind::ReadOnlyFilesystem
+                    ) =>
+            {
+                File::open(path)
+            }
+            opened => opened,
+        }
+        .wrap_err(format!("Cannot open lock file {}", path.to_string_lossy()))?;
+        let started = std::time::Instant::now();
+        loop {
+            let locked = if exclusive {
+                FileExt::try_lock_exclusive(&file)
+            } else {
+                FileExt::try_lock_shared(&file)
+            };
+            match locked {
+                Ok(()) => return Ok(Self(file)),
+                Err(e) if e.kind() == fs2::lock_contended_error().kind() => {
+                    if started.elapsed() >= timeout {
+                        return Err(eyre!(
+                            "Timed out after {:?} waiting for cache lock {}",
+                            timeout,
+                            path.to_string_lossy()
+                        ));
+                    }
+                    trace!("Cache is locked by another process, waiting");
+                    std::thread::sleep(Duration::from_millis(50));
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+impl Drop for CacheLock {
+    fn drop(&mut self) {
+        FileExt::unlock(&self.0).unwrap_or_else(|e| warn!("Cannot release cache lock: {}", e));
+    }
+}
+
+/// First member of every archive, listing the outputs it holds
+const MANIFEST_NAME: &str = ".folca_manifest.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ArchiveManifest {
+    /// Output paths as given when the entry was written, restored by position
+    outputs: Vec<PathBuf>,
+    /// Exit code of the command, only set when its output was captured
+    #[serde(default)]
+    exit_code: Option<i32>,
+    /// Whether timestamps were zeroed, in which case they are not restored
+    #[serde(default)]
+    reproducible: bool,
+}
+
+/// What archives record of the modification times of outputs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ArchiveTimes {
+    /// Whole seconds, as tar headers hold them
+    Seconds,
+    /// Nanoseconds, in a PAX record before each member
+    Exact,
+    /// Zeroed along with ownership, for reproducible archives
+    Zeroed,
+}
+
+/// What restoring an entry does besides unpacking its outputs
+#[derive(Clone, Copy, Debug)]
+struct RestoreOptions {
+    /// Return the captured output, see `--capture`
+    capture: bool,
+    /// See `--touch-output`
+    touch: bool,
+    /// Replay failed runs rather than ignoring them, see `--cache-failures`
+    cache_failures: bool,
+    /// See `--verify-restore`
+    verify: bool,
+    /// See `--max-restore-size`
+    max_size: Option<u64>,
+    /// Digest the entry must have been stored with, see `--collision-guard`
+    guard: Option<blake3::Hash>,
+    /// Only report whether the entry exists
+    dry_run: bool,
+}
+
+/// blake3 digest of the outputs as archived: member names, kinds, link targets and file
+/// contents, in archive order
+#[derive(Default)]
+struct OutputDigest(blake3::Hasher);
+
+impl OutputDigest {
+    /// Adds the member `name` found at `path`. The content of files is to be written next.
+    fn add_member(&mut self, name: &Path, path: &Path, metadata: &std::fs::Metadata) -> Result<()> {
+        let name = portable_path_bytes(name);
+        self.0.update(&(name.len() as u64).to_be_bytes());
+        self.0.update(&name);
+        if metadata.file_type().is_symlink() {
+            let target = std::fs::read_link(path)?;
+            let target = portable_path_bytes(&target);
+            self.0.update(b"l");
+            self.0.update(&(target.len() as u64).to_be_bytes());
+            self.0.update(&target);
+        } else if metadata.is_file() {
+            self.0.update(b"f");
+            self.0.update(&metadata.len().to_be_bytes());
+        } else {
+            self.0.update(b"d");
+        }
+        Ok(())
+    }
+}
+
+/// Adds what is read to a digest
+struct DigestReader<'a, R>(R, &'a mut blake3::Hasher);
+
+impl<R: Read> Read for DigestReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.0.read(buf)?;
+        self.1.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// Fails reads once more than `limit` bytes came through, see `--max-restore-size`
+struct LimitedReader<R> {
+    inner: R,
+    limit: u64,
+    read: u64,
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.read += read as u64;
+        if self.read > self.limit {
+            return Err(std::io::Error::other(format!(
+                "Cached entry expands to more than {}, refusing to restore it",
+                bytefmt::format(self.limit)
+            )));
+        }
+        Ok(read)
+    }
+}
+
+/// Modification time of an archive member recorded with `--preserve-mtime`
+fn exact_mtime<R: Read>(entry: &mut tar::Entry<R>) -> Result<Option<FileTime>> {
+    let extensions = match entry.pax_extensions()? {
+        Some(extensions) => extensions,
+        None => return Ok(None),
+    };
+    for extension in extensions {
+        let extension = extension?;
+        if extension.key() != Ok("mtime") {
+            continue;
+        }
+        let value = extension
+            .value()
+            .map_err(|_| eyre!("Invalid mtime record"))?;
+        let (seconds, fraction) = value.split_once('.').unwrap_or((value, ""));
+        let nanos = format!("{:0<9.9}", fraction);
+        return match (seconds.parse(), nanos.parse()) {
+            (Ok(seconds), Ok(nanos)) => Ok(Some(FileTime::from_unix_time(seconds, nanos))),
+            _ => Err(eyre!("Invalid mtime record {:?}", value)),
+        };
+    }
+    Ok(None)
+}
+
+const STDOUT_NAME: &str = ".folca_stdout";
+const STDERR_NAME: &str = ".folca_stderr";
+
+/// Streams and exit code of a command run with `--capture`
+#[derive(Debug, Default)]
+pub struct CapturedOutput {
+    /// Exit code of the command, with signals reported as 128 plus their number
+    pub exit_code: i32,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+impl CapturedOutput {
+    /// Runs the command, forwarding its output to ours while recording it
+    fn run(
+        command: &mut std::process::Command,
+        timeout: Option<Duration>,
+    ) -> Result<(ExitStatus, Self)> {
+        let mut child = command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| start_error(command, e))?;
+        let stdout = child.stdout.take().map(|pipe| tee(pipe, std::io::stdout));
+        let stderr = child.stderr.take().map(|pipe| tee(pipe, std::io::stderr));
+        let exit_status = wait_for(&mut child, timeout)?;
+
+        let join = |handle: Option<JoinHandle<std::io::Result<Vec<u8>>>>| -> Result<Vec<u8>> {
+            match handle {
+                Some(handle) => handle
+                    .join()
+                    .map_err(|_| eyre!("Output capture thread panicked"))?
+                    .wrap_err("Cannot capture command output"),
+                None => Ok(Vec::new()),
+            }
+        };
+        let captured = Self {
+            exit_code: exit_code(exit_status),
+            stdout: join(stdout)?,
+            stderr: join(stderr)?,
+        };
+        Ok((exit_status, captured))
+    }
+
+    pub fn replay(&self) -> Result<()> {
+        let mut stdout = std::io::stdout();
+        stdout.write_all(&self.stdout)?;
+        stdout.flush()?;
+        let mut stderr = std::io::stderr();
+        stderr.write_all(&self.stderr)?;
+        stderr.flush()?;
+        Ok(())
+    }
+}
+
+/// Shell and its flag that runs a command line with `--shell`
+#[cfg(not(windows))]
+const SHELL: [&str; 2] = ["sh", "-c"];
+#[cfg(windows)]
+const SHELL: [&str; 2] = ["cmd", "/C"];
+
+/// Shells exit with this code when the command does not exist
+const COMMAND_NOT_FOUND_EXIT_CODE: i32 = 127;
+
+/// The program of the command does not exist, most likely a typo
+#[derive(Debug)]
+struct CommandNotFound(String);
+
+impl std::fmt::Display for CommandNotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "command not found: {}", self.0)
+    }
+}
+
+impl std::error::Error for CommandNotFound {}
+
+/// `timeout` exits with this code when the command ran out of time
+const TIMEOUT_EXIT_CODE: i32 = 124;
+
+/// The command was killed after running for longer than `--timeout`
+#[derive(Debug)]
+struct CommandTimedOut(Duration);
+
+impl std::fmt::Display for CommandTimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Command timed out after {}",
+            humantime::format_duration(self.0)
+        )
+    }
+}
+
+impl std::error::Error for CommandTimedOut {}
+
+/// Waits for the command to exit, killing it once `timeout` has elapsed
+fn wait_for(child: &mut std::process::Child, timeout: Option<Duration>) -> Result<ExitStatus> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return Ok(child.wait()?),
+    };
+    let started = std::time::Instant::now();
+    loop {
+        if let Some(exit_status) = child.try_wait()? {
+            return Ok(exit_status);
+        }
+        let elapsed = started.elapsed();
+        if elapsed >= timeout {
+            kill_process_group(child)?;
+            child.wait()?;
+            return Err(CommandTimedOut(timeout).into());
+        }
+        std::thread::sleep((timeout - elapsed).min(Duration::from_millis(50)));
+    }
+}
+
+/// Starts the command in a process group of its own, so that anything it spawns can be killed
+/// along with it
+#[cfg(unix)]
+fn own_process_group(command: &mut std::process::Command) {
+    use std::os::unix::process::CommandExt;
+    command.process_group(0);
+}
+
+#[cfg(not(unix))]
+fn own_process_group(_: &mut std::process::Command) {}
+
+/// Kills the command started by `own_process_group` and everything left in its group, which
+/// would otherwise keep running and hold our end of its output pipes open
+#[cfg(unix)]
+fn kill_process_group(child: &mut std::process::Child) -> Result<()> {
+    // The command leads its group, so the group id is its pid
+    if unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) } == 0 {
+        Ok(())
+    } else {
+        Ok(child.kill()?)
+    }
+}
+
+#[cfg(not(unix))]
+fn kill_process_group(child: &mut std::process::Child) -> Result<()> {
+    Ok(child.kill()?)
+}
+
+/// Error for a command that could not be started, telling a missing program from other failures
+fn start_error(command: &std::process::Command, error: std::io::Error) -> Report {
+    let program = command.get_program().to_string_lossy().into_owned();
+    if error.kind() == std::io::ErrorKind::NotFound {
+        CommandNotFound(program).into()
+    } else {
+        Report::new(error).wrap_err(format!("Cannot start command {}", program))
+    }
+}
+
+/// Exit code to report for the command, following the shell convention of 128 + signal number
+/// when it was killed by a signal
+fn exit_code(exit_status: ExitStatus) -> i32 {
+    exit_status
+        .code()
+        .or_else(|| exit_signal(exit_status).map(|signal| 128 + signal))
+        .unwrap_or(1)
+}
+
+#[cfg(unix)]
+fn exit_signal(exit_status: ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+    exit_status.signal()
+}
+
+#[cfg(not(unix))]
+fn exit_signal(_: ExitStatus) -> Option<i32> {
+    None
+}
+
+/// How paths are turned into bytes before being hashed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PathNormalization {
+    /// The platform's own representation
+    Exact,
+    /// Components joined with `/`, so a relative path hashes the same on every platform
+    Portable,
+    /// Portable and lowercased, for trees checked out on case-insensitive filesystems
+    Lowercase,
+}
+
+impl PathNormalization {
+    const VARIANTS: &'static [&'static str] = &["exact", "portable", "lowercase"];
+
+    fn path_bytes(self, path: &Path) -> Cow<'_, [u8]> {
+        match self {
+            PathNormalization::Exact => native_path_bytes(path),
+            PathNormalization::Portable => portable_path_bytes(path),
+            PathNormalization::Lowercase => {
+                let bytes = portable_path_bytes(path);
+                Cow::Owned(match std::str::from_utf8(&bytes) {
+                    Ok(path) => path.to_lowercase().into_bytes(),
+                    Err(_) => bytes.to_ascii_lowercase(),
+                })
+            }
+        }
+    }
+}
+
+impl FromStr for PathNormalization {
+    type Err = &'static str;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "exact" => Ok(PathNormalization::Exact),
+            "portable" => Ok(PathNormalization::Portable),
+            "lowercase" => Ok(PathNormalization::Lowercase),
+            _ => Err("Unknown path normalization"),
+        }
+    }
+}
+
+impl std::fmt::Display for PathNormalization {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            PathNormalization::Exact => "exact",
+            PathNormalization::Portable => "portable",
+            PathNormalization::Lowercase => "lowercase",
+        })
+    }
+}
+
+fn is_symlink_loop(error: &ignore::Error) -> bool {
+    match error {
+        ignore::Error::Loop { .. } => true,
+        ignore::Error::WithPath { err, .. }
+        | ignore::Error::WithDepth { err, .. }
+        | ignore::Error::WithLineNumber { err, .. } => is_symlink_loop(err),
+        _ => false,
+    }
+}
+
+/// Symlink a walk error is about when it could not be followed as it points nowhere
+fn dangling_link(error: &ignore::Error) -> Option<&Path> {
+    match error {
+        ignore::Error::WithPath { path, err } => match **err {
+            ignore::Error::Io(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let is_link = path
+                    .symlink_metadata()
+                    .is_ok_and(|m| m.file_type().is_symlink());
+                Some(path.as_path()).filter(|_| is_link)
+            }
+            _ => dangling_link(err),
+        },
+        ignore::Error::WithDepth { err, .. } | ignore::Error::WithLineNumber { err, .. } => {
+            dangling_link(err)
+        }
+        _ => None,
+    }
+}
+
+/// Permission bits of a file, only the read-only flag is available outside Unix
+#[cfg(unix)]
+fn file_mode(metadata: &std::fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn file_mode(metadata: &std::fs::Metadata) -> u32 {
+    if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+#[cfg(unix)]
+fn native_path_bytes(path: &Path) -> Cow<'_, [u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    Cow::Borrowed(path.as_os_str().as_bytes())
+}
+
+#[cfg(not(unix))]
+fn native_path_bytes(path: &Path) -> Cow<'_, [u8]> {
+    match path.to_string_lossy() {
+        Cow::Borrowed(path) => Cow::Borrowed(path.as_bytes()),
+        Cow::Owned(path) => Cow::Owned(path.into_bytes()),
+    }
+}
+
+/// Unix paths already use `/` only, so their raw bytes are kept
+#[cfg(unix)]
+fn portable_path_bytes(path: &Path) -> Cow<'_, [u8]> {
+    native_path_bytes(path)
+}
+
+#[cfg(not(unix))]
+fn portable_path_bytes(path: &Path) -> Cow<'_, [u8]> {
+    let mut bytes = Vec::new();
+    for component in path.components() {
+        if !matches!(bytes.last(), None | Some(b'/')) {
+            bytes.push(b'/');
+        }
+        match component {
+            Component::RootDir if bytes.is_empty() => bytes.push(b'/'),
+            Component::RootDir => {}
+            component => {
+                bytes.extend_from_slice(component.as_os_str().to_string_lossy().as_bytes())
+            }
+        }
+    }
+    Cow::Owned(bytes)
+}
+
+/// Copies everything read from `pipe` to the stream given by `sink`, returning a copy of it
+fn tee<R, W, F>(mut pipe: R, sink: F) -> JoinHandle<std::io::Result<Vec<u8>>>
+where
+    R: Read + Send + 'static,
+    W: Write,
+    F: FnOnce() -> W + Send + 'static,
+{
+    std::thread::spawn(move || {
+        let mut sink = sink();
+        let mut forwarding = true;
+        let mut recorded = Vec::new();
+        let mut buffer = [0u8; 8192];
+        loop {
+            let bytes_read = pipe.read(&mut buffer)?;
+            if bytes_read == 0 {
+                break;
+            }
+            // Keep draining the pipe if our own output went away, so the command is not blocked
+            if forwarding {
+                forwarding = sink
+                    .write_all(&buffer[..bytes_read])
+                    .and_then(|_| sink.flush())
+                    .is_ok();
+            }
+            recorded.extend_from_slice(&buffer[..bytes_read]);
+        }
+        Ok(recorded)
+    })
+}
+
+/// Bytes read from the outputs to estimate how well they compress
+const COMPRESSIBILITY_SAMPLE: usize = 1 << 20;
+/// Bytes sampled from each file, so that the first large one does not make up the whole sample
+const COMPRESSIBILITY_CHUNK: usize = 64 << 10;
+
+/// Size of a quickly deflated sample of the outputs relative to the sample, `None` when they
+/// hold no data
+fn compressibility(output_paths: &[&Path]) -> Result<Option<f64>> {
+    let mut sample = Vec::new();
+    'outputs: for output_path in output_paths {
+        for entry in WalkDir::new(output_path).sort_by_file_name() {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let budget = (COMPRESSIBILITY_SAMPLE - sample.len()).min(COMPRESSIBILITY_CHUNK);
+            File::open(entry.path())?
+                .take(budget as u64)
+                .read_to_end(&mut sample)?;
+            if sample.len() >= COMPRESSIBILITY_SAMPLE {
+                break 'outputs;
+            }
+        }
+    }
+    if sample.is_empty() {
+        return Ok(None);
+    }
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(&sample)?;
+    Ok(Some(encoder.finish()?.len() as f64 / sample.len() as f64))
+}
+
+/// Sets the access and modification times of the outputs and everything below them to now
+fn touch_outputs(output_paths: &[&Path]) -> Result<()> {
+    let now = FileTime::now();
+    for output_path in output_paths {
+        for entry in WalkDir::new(output_path) {
+            filetime::set_symlink_file_times(entry?.path(), now, now)?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes a file, then the directories it leaves empty up to `root`, whether or not the file
+/// was still there
+fn remove_file_and_empty_dirs(root: &Path, path: &Path) -> std::io::Result<()> {
+    let removed = std::fs::remove_file(path);
+    remove_empty_dirs(root, path);
+    removed
+}
+
+/// Removes the empty directories above `path`, up to but excluding `root`
+fn remove_empty_dirs(root: &Path, path: &Path) {
+    for dir in path.ancestors().skip(1) {
+        // Only empty directories can be removed, which stops at the first one still in use
+        if dir == root || !dir.starts_with(root) || std::fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+}
+
+/// Renames a file, copying it over when `to` is on another filesystem, such as a cache directory
+/// that is a separate mount
+fn rename_or_copy(from: &Path, to: &Path) -> std::io::Result<()> {
+    rename_or_copy_with(from, to, |from, to| std::fs::rename(from, to))
+}
+
+/// `rename_or_copy` renaming with `rename`, which tests use to fail like a rename across
+/// filesystems would
+fn rename_or_copy_with(
+    from: &Path,
+    to: &Path,
+    rename: impl FnOnce(&Path, &Path) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    match rename(from, to) {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            trace!(
+                "{} is on another filesystem, copying it",
+                to.to_string_lossy()
+            );
+            copy_into_place(from, to)?;
+            std::fs::remove_file(from)
+        }
+        renamed => renamed,
+    }
+}
+
+/// Copies a file next to `to` then renames it there, so that `to` is never seen half written
+fn copy_into_place(from: &Path, to: &Path) -> std::io::Result<()> {
+    let temp_path = temp_path(to);
+    let copied = std::fs::copy(from, &temp_path).and_then(|_| std::fs::rename(&temp_path, to));
+    if copied.is_err() {
+        std::fs::remove_file(&temp_path).unwrap_or_else(|e| trace!("{}", e));
+    }
+    copied
+}
+
+const TEMP_INFIX: &str = ".tmp.";
+
+/// Sibling path a file is written to before being renamed into place
+fn temp_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(format!("{}{}", TEMP_INFIX, std::process::id()));
+    path.with_file_name(file_name)
+}
+
+fn is_temp_path(path: &Path) -> bool {
+    path.file_name()
+        .map(|name| name.to_string_lossy().contains(TEMP_INFIX))
+        .unwrap_or(false)
+}
+
+/// Number and size of the chunks of tar data in flight between reading outputs and compressing
+const TAR_CHANNEL_CHUNKS: usize = 16;
+const TAR_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Sends everything written to it over a channel, one chunk per write
+struct ChannelWriter(std::sync::mpsc::SyncSender<Vec<u8>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.send(buf.to_vec()).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "Archive is no longer written",
+            )
+        })?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Where entries are stored relative to the cache path: `<command>/<input>.<extension>`, below
+/// a directory for the first byte of the command hash with `shard`, and with a directory for the
+/// first two digits of the input hash in between with `shard_inputs`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct CacheLayout {
+    shard: bool,
+    shard_inputs: bool,
+}
+
+impl CacheLayout {
+    const ALL: [CacheLayout; 4] = [
+        CacheLayout {
+            shard: false,
+            shard_inputs: false,
+        },
+        CacheLayout {
+            shard: true,
+            shard_inputs: false,
+        },
+        CacheLayout {
+            shard: false,
+            shard_inputs: true,
+        },
+        CacheLayout {
+            shard: true,
+            shard_inputs: true,
+        },
+    ];
+    /// Bounds of how many levels below the cache path entries are, across layouts
+    const MIN_DEPTH: usize = 2;
+    const MAX_DEPTH: usize = 4;
+
+    fn entry_name(self, key: &CommandInputHashes, extension: &str) -> String {
+        let mut name = String::new();
+        if self.shard {
+            name.push_str(&format!("{:02x}/", key.command_hash >> 56));
+        }
+        name.push_str(&format!("{:x}/", key.command_hash));
+        let input = format!("{:016x}", key.input_hash);
+        if self.shard_inputs {
+            name.push_str(&format!("{}/", &input[..2]));
+        }
+        name.push_str(&format!("{}.{}", input, extension));
+        name
+    }
+
+    /// Matches entry names relative to the cache path, capturing the hashes and the extension
+    fn regex(self) -> Regex {
+        let shard_dir = "[[:a-z0-9:]]{2}/";
+        Regex::new(&format!(
+            r"(?-u)^{}([[:a-z0-9:]]+)/{}([[:a-z0-9:]]{{16}})\.(tar|tar\.gz|tar\.zst)$",
+            if self.shard { shard_dir } else { "" },
+            if self.shard_inputs { shard_dir } else { "" },
+        ))
+        .unwrap()
+    }
+}
+
+/// Absolute form of a path, with `.` and `..` resolved without looking at the filesystem, as the
+/// path may not exist yet
+fn absolute_path(path: &Path) -> Result<PathBuf> {
+    let mut absolute = std::env::current_dir()?;
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                absolute.pop();
+            }
+            // Roots and prefixes replace what was there, as for an absolute path
+            component => absolute.push(component),
+        }
+    }
+    Ok(absolute)
+}
+
+/// Whether a file name ends like one of the archives folca writes
+fn looks_like_archive(name: &str) -> bool {
+    CompressionAlgo::VARIANTS
+        .iter()
+        .filter_map(|variant| variant.parse::<CompressionAlgo>().ok())
+        .any(|codec| name.ends_with(&format!(".{}", codec.extension())))
+}
+
+fn archive_checksum(path: &Path) -> Result<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+    std::io::copy(&mut BufReader::new(File::open(path)?), &mut hasher)?;
+    Ok(hasher.finalize())
+}
+
+#[derive(Clone, Copy, Debug)]
+struct LastUsedAndSize {
+    last_used: std::time::SystemTime,
+    size: u64,
+    compression: CompressionAlgo,
+    checksum: Option<blake3::Hash>,
+    /// Digest of the outputs, compared to that of restored ones with `--verify-restore`
+    output_digest: Option<blake3::Hash>,
+    /// Whether the entry records a failed run rather than outputs, see `--cache-failures`
+    failed: bool,
+    /// Digest of the zstd dictionary the archive was compressed with, see `--dict`
+    dictionary: Option<blake3::Hash>,
+    /// Second digest of the command and inputs, see `--collision-guard`
+    guard: Option<blake3::Hash>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum CompressionAlgo {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl CompressionAlgo {
+    const VARIANTS: &'static [&'static str] = &["none", "gzip", "zstd"];
+
+    fn extension(self) -> &'static str {
+        match self {
+            CompressionAlgo::None => "tar",
+            CompressionAlgo::Gzip => "tar.gz",
+            CompressionAlgo::Zstd => "tar.zst",
+        }
+    }
+
+    fn from_extension(extension: &str) -> Option<Self> {
+        match extension {
+            "tar" => Some(CompressionAlgo::None),
+            "tar.gz" => Some(CompressionAlgo::Gzip),
+            "tar.zst" => Some(CompressionAlgo::Zstd),
+            _ => None,
+        }
+    }
+
+    fn decoder(self, file: File, dictionary: Option<&[u8]>) -> std::io::Result<Box<dyn Read>> {
+        Ok(match (self, dictionary) {
+            (CompressionAlgo::None, _) => Box::new(file),
+            (CompressionAlgo::Gzip, _) => Box::new(GzDecoder::new(file)),
+            (CompressionAlgo::Zstd, Some(dictionary)) => Box::new(zstd::Decoder::with_dictionary(
+                BufReader::new(file),
+                dictionary,
+            )?),
+            (CompressionAlgo::Zstd, None) => Box::new(zstd::Decoder::new(file)?),
+        })
+    }
+}
+
+/// How to read an archive back
+#[derive(Clone, Copy)]
+struct ArchiveReader<'a> {
+    compression: CompressionAlgo,
+    /// See `--dict`
+    dictionary: Option<&'a [u8]>,
+    /// See `--max-restore-size`
+    max_size: Option<u64>,
+}
+
+impl ArchiveReader<'_> {
+    /// Decoder of the archive at `path`, failing past `max_size` decompressed bytes
+    fn open(self, path: &Path) -> std::io::Result<Box<dyn Read>> {
+        let decoder = self
+            .compression
+            .decoder(File::open(path)?, self.dictionary)?;
+        Ok(match self.max_size {
+            Some(limit) => Box::new(LimitedReader {
+                inner: decoder,
+                limit,
+                read: 0,
+            }),
+            None => decoder,
+        })
+    }
+}
+
+/// Source of the current time of an `Inventory`
+struct Clock(Box<dyn Fn() -> SystemTime + Send + Sync>);
+
+impl Clock {
+    fn now(&self) -> SystemTime {
+        (self.0)()
+    }
+}
+
+impl std::fmt::Debug for Clock {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Clock")
+    }
+}
+
+/// A zstd dictionary along with its digest, which entries compressed with it record
+struct ZstdDictionary {
+    data: Vec<u8>,
+    hash: blake3::Hash,
+}
+
+/// Magic number of the zstd skippable frame archives start with when compressed with a dictionary
+const DICTIONARY_FRAME_MAGIC: u32 = 0x184d_2a5d;
+
+impl ZstdDictionary {
+    fn load(path: &Path) -> Result<Self> {
+        let data = std::fs::read(path)
+            .wrap_err(format!("Cannot read dictionary {}", path.to_string_lossy()))?;
+        Ok(Self {
+            hash: blake3::hash(&data),
+            data,
+        })
+    }
+
+    /// Writes the digest as a skippable frame, which decoders pass over, so that the archive
+    /// tells which dictionary it needs when the index is rebuilt or it is fetched
+    fn write_frame<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(&DICTIONARY_FRAME_MAGIC.to_le_bytes())?;
+        writer.write_all(&(blake3::OUT_LEN as u32).to_le_bytes())?;
+        writer.write_all(self.hash.as_bytes())
+    }
+
+    /// Digest of the dictionary the archive at `path` was compressed with, if any
+    fn recorded_in(path: &Path, compression: CompressionAlgo) -> Result<Option<blake3::Hash>> {
+        if compression != CompressionAlgo::Zstd {
+            return Ok(None);
+        }
+        let mut frame = [0u8; 8 + blake3::OUT_LEN];
+        match File::open(path)?.read_exact(&mut frame) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let (header, digest) = frame.split_at(8);
+        if header[..4] != DICTIONARY_FRAME_MAGIC.to_le_bytes()
+            || header[4..] != (blake3::OUT_LEN as u32).to_le_bytes()
+        {
+            return Ok(None);
+        }
+        let mut hash = [0u8; blake3::OUT_LEN];
+        hash.copy_from_slice(digest);
+        Ok(Some(blake3::Hash::from(hash)))
+    }
+}
+
+impl std::fmt::Debug for ZstdDictionary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "ZstdDictionary({})", self.hash.to_hex())
+    }
+}
+
+impl FromStr for CompressionAlgo {
+    type Err = &'static str;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "none" => Ok(CompressionAlgo::None),
+            "gzip" => Ok(CompressionAlgo::Gzip),
+            "zstd" => Ok(CompressionAlgo::Zstd),
+            _ => Err("Unknown compression"),
+        }
+    }
+}
+
+impl std::fmt::Display for CompressionAlgo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            CompressionAlgo::None => "none",
+            CompressionAlgo::Gzip => "gzip",
+            CompressionAlgo::Zstd => "zstd",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum HashAlgo {
+    Fnv1a,
+    Blake3,
+}
+
+impl HashAlgo {
+    const VARIANTS: &'static [&'static str] = &["fnv1a", "blake3"];
+}
+
+impl FromStr for HashAlgo {
+    type Err = &'static str;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "fnv1a" => Ok(HashAlgo::Fnv1a),
+            "blake3" => Ok(HashAlgo::Blake3),
+            _ => Err("Unknown hash algorithm"),
+        }
+    }
+}
+
+impl std::fmt::Display for HashAlgo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            HashAlgo::Fnv1a => "fnv1a",
+            HashAlgo::Blake3 => "blake3",
+        })
+    }
+}
+
+/// Hash function the cache key is computed with. Hashers are cloned to log intermediate states.
+trait ContentHasher: Clone {
+    fn write(&mut self, bytes: &[u8]);
+    fn finish(self) -> u64;
+
+    /// What the hash of an input file is folded into the key as
+    fn file_digest(self) -> FileDigest {
+        FileDigest::Short(self.finish())
+    }
+}
+
+/// 64-bit FNV-1a, which unlike `DefaultHasher` is fixed across platforms and Rust versions
+#[derive(Clone)]
+struct Fnv1aHasher(u64);
+
+impl Default for Fnv1aHasher {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl ContentHasher for Fnv1aHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    fn finish(self) -> u64 {
+        self.0
+    }
+}
+
+impl ContentHasher for blake3::Hasher {
+    fn write(&mut self, bytes: &[u8]) {
+        self.update(bytes);
+    }
+
+    /// The digest is truncated to its first 8 bytes to keep the cache layout intact
+    fn finish(self) -> u64 {
+        let mut prefix = [0u8; 8];
+        prefix.copy_from_slice(&self.finalize().as_bytes()[..8]);
+        u64::from_be_bytes(prefix)
+    }
+}
+
+/// blake3 keyed apart from `--hash-algo blake3`, whose file digests are kept whole, for
+/// `--collision-guard`
+#[derive(Clone)]
+struct GuardHasher(blake3::Hasher);
+
+impl GuardHasher {
+    fn digest(self) -> blake3::Hash {
+        self.0.finalize()
+    }
+}
+
+impl Default for GuardHasher {
+    fn default() -> Self {
+        Self(blake3::Hasher::new_derive_key("folca collision guard"))
+    }
+}
+
+impl ContentHasher for GuardHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    fn finish(self) -> u64 {
+        self.0.finish()
+    }
+
+    fn file_digest(self) -> FileDigest {
+        FileDigest::Full(self.digest())
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct CacheStats {
+    entries: usize,
+    total_size: u64,
+    oldest_last_used: Option<String>,
+    newest_last_used: Option<String>,
+    largest: Vec<CacheEntryStats>,
+}
+
+#[derive(Debug, Serialize)]
+struct CacheEntryStats {
+    path: PathBuf,
+    size: u64,
+    last_used: String,
+}
+
+impl std::fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "Entries:     {}", self.entries)?;
+        writeln!(f, "Total size:  {}", bytefmt::format(self.total_size))?;
+        if let (Some(oldest), Some(newest)) = (&self.oldest_last_used, &self.newest_last_used) {
+            writeln!(f, "Oldest used: {}", oldest)?;
+            writeln!(f, "Newest used: {}", newest)?;
+        }
+        if !self.largest.is_empty() {
+            writeln!(f, "Largest entries:")?;
+            for entry in &self.largest {
+                writeln!(
+                    f,
+                    "  {:>10}  {}  {}",
+                    bytefmt::format(entry.size),
+                    entry.last_used,
+                    entry.path.to_string_lossy()
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Outcome of `folca verify`
+#[derive(Debug, Default)]
+struct VerifyReport {
+    /// Entries read through
+    checked: usize,
+    /// Entries that could not be read back, along with why
+    corrupt: Vec<(PathBuf, String)>,
+    /// Entries that need a dictionary other than `--dict`, along with why
+    unchecked: Vec<(PathBuf, String)>,
+}
+
+impl std::fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (path, reason) in &self.corrupt {
+            writeln!(f, "{}: {}", path.to_string_lossy(), reason)?;
+        }
+        for (path, reason) in &self.unchecked {
+            writeln!(f, "{}: {}, not checking it", path.to_string_lossy(), reason)?;
+        }
+        write!(
+            f,
+            "Checked {} entries, {} corrupt",
+            self.checked,
+            self.corrupt.len()
+        )?;
+        if !self.unchecked.is_empty() {
+            write!(f, ", {} left unchecked", self.unchecked.len())?;
+        }
+        writeln!(f)
+    }
+}
+
+/// Outcomes of cache lookups since the cache was created, not counting dry runs
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct HitCounters {
+    hits: u64,
+    misses: u64,
+    /// Total size of the archives restored from
+    restored_bytes: u64,
+}
+
+impl HitCounters {
+    const FILE_NAME: &'static str = ".hit_counters.json";
+}
+
+impl std::fmt::Display for HitCounters {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let lookups = self.hits + self.misses;
+        writeln!(f, "Hits:        {}", self.hits)?;
+        writeln!(f, "Misses:      {}", self.misses)?;
+        if lookups > 0 {
+            writeln!(
+                f,
+                "Hit rate:    {:.1}%",
+                self.hits as f64 * 100.0 / lookups as f64
+            )?;
+        }
+        writeln!(f, "Restored:    {}", bytefmt::format(self.restored_bytes))
+    }
+}
+
+fn format_time(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn path_bytes_keep_non_utf8_and_backslashes_on_unix() {
+        use std::os::unix::ffi::OsStrExt;
+        let non_utf8 = Path::new(std::ffi::OsStr::from_bytes(b"dir/caf\xe9.txt"));
+        for normalization in [PathNormalization::Exact, PathNormalization::Portable] {
+            assert_eq!(&*normalization.path_bytes(non_utf8), b"dir/caf\xe9.txt");
+            // A backslash is part of a file name on Unix, not a separator
+            assert_eq!(
+                &*normalization.path_bytes(Path::new("dir\\file.txt")),
+                b"dir\\file.txt"
+            );
+        }
+        assert_eq!(
+            &*PathNormalization::Lowercase.path_bytes(non_utf8),
+            b"dir/caf\xe9.txt"
+        );
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn path_bytes_join_components_with_slashes_on_windows() {
+        assert_eq!(
+            &*PathNormalization::Portable.path_bytes(Path::new("dir\\sub\\file.txt")),
+            b"dir/sub/file.txt"
+        );
+        assert_eq!(
+            &*PathNormalization::Portable.path_bytes(Path::new("dir/sub\\file.txt")),
+            b"dir/sub/file.txt"
+        );
+    }
+
+    #[test]
+    fn portable_path_bytes_use_slashes_whatever_the_platform_separator() {
+        // Joined with `\` on Windows and `/` elsewhere
+        let native: PathBuf = ["src", "sub", "main.c"].iter().collect();
+        for normalization in [PathNormalization::Portable, PathNormalization::Lowercase] {
+            assert_eq!(&*normalization.path_bytes(&native), b"src/sub/main.c");
+        }
+    }
+
+    /// Empty directory for a single test, holding `files` named after their contents
+    fn scratch_dir(name: &str, files: &[&str]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("folca-unit-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        for file in files {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, file).unwrap();
+        }
+        dir
+    }
+
+    /// Options of a run of `command` caching `dir/in` to `dir/out` in `dir/cache`
+    fn config_for(dir: &Path, command: &str) -> Config {
+        config_with(dir, &[], command)
+    }
+
+    /// `config_for` with `options` given as well
+    fn config_with(dir: &Path, options: &[&str], command: &str) -> Config {
+        let mut args = vec![OsString::from("folca")];
+        args.extend(options.iter().map(OsString::from));
+        args.extend([
+            OsString::from("--cache-path"),
+            dir.join("cache").into(),
+            dir.join("in").into(),
+            dir.join("out").into(),
+            OsString::from("--"),
+            OsString::from(command),
+        ]);
+        Config::parse_args(args).unwrap()
+    }
+
+    #[test]
+    fn hashing_inputs_advances_the_progress_once_per_file() {
+        let dir = scratch_dir(
+            "progress",
+            &[
+                "in/a.txt",
+                "in/b.txt",
+                "in/sub/c.txt",
+                "in/sub/deeper/d.txt",
+            ],
+        );
+        let config = config_for(&dir, "true");
+
+        let progress = ProgressBar::hidden();
+        let hashed = config.input_hasher(Fnv1aHasher::default, false, 0, &progress);
+        std::fs::remove_dir_all(&dir).unwrap();
+        hashed.unwrap();
+        assert_eq!(progress.position(), 4);
+    }
+
+    #[test]
+    fn streamed_outputs_are_the_serial_archive() {
+        let dir = scratch_dir("stream", &["out/a.txt", "out/sub/b.txt", "log.txt"]);
+        // Spans several chunks of the channel
+        let large: Vec<u8> = (0..3 * TAR_CHUNK_SIZE as u32)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        std::fs::write(dir.join("out/sub/large.bin"), large).unwrap();
+        let (out, log) = (dir.join("out"), dir.join("log.txt"));
+        let outputs = [out.as_path(), log.as_path()];
+
+        for times in [
+            ArchiveTimes::Seconds,
+            ArchiveTimes::Exact,
+            ArchiveTimes::Zeroed,
+        ] {
+            let serial = Inventory::append_outputs(&outputs, None, Vec::new(), times).unwrap();
+            let streamed = Inventory::stream_outputs(&outputs, None, Vec::new(), times).unwrap();
+            assert!(serial.0 == streamed.0, "{:?} archives differ", times);
+            assert_eq!(serial.1, streamed.1);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hash_functions_give_their_own_consistent_keys() {
+        let dir = scratch_dir("hashers", &["in/a.txt", "in/sub/b.txt"]);
+        let config = config_for(&dir, "true");
+        let keys = || {
+            (
+                config.command_input_key_with(Fnv1aHasher::default).unwrap(),
+                config.command_input_key_with(blake3::Hasher::new).unwrap(),
+            )
+        };
+
+        let (fnv1a, blake3) = keys();
+        assert_ne!(fnv1a.command_hash, blake3.command_hash);
+        assert_ne!(fnv1a.input_hash, blake3.input_hash);
+        assert_eq!(keys(), (fnv1a, blake3));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn evicting_entries_deleted_behind_the_inventory_completes() {
+        let dir = scratch_dir("evict-deleted", &["in/a.txt", "out/built.txt"]);
+        let (deleted, kept) = (config_for(&dir, "true"), config_for(&dir, "false"));
+        let mut inventory = Inventory::open(&deleted).unwrap();
+        let deleted_key = compute_key(&deleted).unwrap();
+        store(&mut inventory, &deleted, &deleted_key, None, None).unwrap();
+        let kept_key = compute_key(&kept).unwrap();
+        store(&mut inventory, &kept, &kept_key, None, None).unwrap();
+
+        // What another process clearing the cache would leave
+        let value = inventory.inv[&deleted_key];
+        std::fs::remove_file(inventory.to_path(&deleted_key, value.compression)).unwrap();
+        inventory
+            .discard_until(&kept_key, u64::MAX, Some(1))
+            .unwrap();
+
+        assert!(!inventory.inv.contains_key(&deleted_key));
+        let reopened = Inventory::open(&kept).unwrap();
+        assert_eq!(
+            reopened.inv.keys().collect::<Vec<_>>(),
+            [&kept_key],
+            "deleted entry is still indexed"
+        );
+        assert!(reopened.to_path(&kept_key, value.compression).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// What a logger set up with `config` writes for a `level` record
+    fn logged(config: simplelog::Config, level: log::Level) -> String {
+        #[derive(Clone, Default)]
+        struct Shared(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let written = Shared::default();
+        let logger = simplelog::WriteLogger::new(log::LevelFilter::Trace, config, written.clone());
+        log::Log::log(
+            &*logger,
+            &log::Record::builder()
+                .level(level)
+                .args(format_args!("message"))
+                .build(),
+        );
+        let bytes = written.0.lock().unwrap().clone();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn log_time_format_maps_to_the_logger_config() {
+        let parse = |args: &[&str]| {
+            let mut args = ["folca"].iter().chain(args).collect::<Vec<_>>();
+            args.extend(["in", "out", "--", "true"].iter());
+            Config::parse_args(args.into_iter().map(OsString::from)).unwrap()
+        };
+
+        // Without a format only debug and trace lines are timed, with minutes and microseconds
+        let default = parse(&[]);
+        let warn_line = logged(default.logger_config(), log::Level::Warn);
+        assert!(warn_line.starts_with("[WARN] message"), "{}", warn_line);
+        let trace_line = logged(default.logger_config(), log::Level::Trace);
+        assert!(
+            Regex::new(r"^\d\d:\d\d\.\d{6} \[TRACE\]")
+                .unwrap()
+                .is_match(trace_line.as_bytes()),
+            "{}",
+            trace_line
+        );
+
+        // A format times every line with it, in local time
+        let timed = parse(&["--log-time-format", "<%Y>"]);
+        let year = chrono::Local::now().format("<%Y>").to_string();
+        for level in [log::Level::Error, log::Level::Warn, log::Level::Trace] {
+            let line = logged(timed.logger_config(), level);
+            assert!(line.starts_with(&year), "{}", line);
+        }
+        assert!(Config::parse_args([
+            "folca",
+            "--log-time-format",
+            "%Q",
+            "in",
+            "out",
+            "--",
+            "true"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn durations_parse_every_unit_and_reject_the_rest() {
+        let parsed = |input| Config::parse_duration(input).unwrap();
+        assert_eq!(parsed("500ms"), Duration::from_millis(500));
+        assert_eq!(parsed("30s"), Duration::from_secs(30));
+        assert_eq!(parsed("5m"), Duration::from_secs(5 * 60));
+        assert_eq!(parsed("2h"), Duration::from_secs(2 * 3600));
+        assert_eq!(parsed("7d"), Duration::from_secs(7 * 86400));
+        assert_eq!(parsed("1h 30m"), Duration::from_secs(5400));
+        assert_eq!(parsed("1.5h"), Duration::from_secs(5400));
+        assert_eq!(parsed("0s"), Duration::new(0, 0));
+
+        for invalid in ["", "30", "s", "-5s", "5 parsecs", "forever"] {
+            assert!(Config::parse_duration(invalid).is_err(), "{:?}", invalid);
+        }
+        assert!(Config::non_zero_duration("0s").is_err());
+        assert_eq!(
+            Config::non_zero_duration("1ms").unwrap(),
+            Duration::from_millis(1)
+        );
+    }
+
+    #[test]
+    fn renames_across_filesystems_fall_back_to_copying() {
+        let dir = scratch_dir("exdev", &["moved.txt", "kept.txt"]);
+        let cross_device =
+            |_: &Path, _: &Path| Err(std::io::Error::from(std::io::ErrorKind::CrossesDevices));
+        rename_or_copy_with(&dir.join("moved.txt"), &dir.join("copy.txt"), cross_device).unwrap();
+        assert!(!dir.join("moved.txt").exists());
+        assert_eq!(
+            std::fs::read_to_string(dir.join("copy.txt")).unwrap(),
+            "moved.txt"
+        );
+        assert!(!temp_path(&dir.join("copy.txt")).exists());
+
+        // Any other failure is not worked around
+        let denied =
+            |_: &Path, _: &Path| Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied));
+        let error =
+            rename_or_copy_with(&dir.join("kept.txt"), &dir.join("other.txt"), denied).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+        assert!(dir.join("kept.txt").exists());
+        assert!(!dir.join("other.txt").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn verify_tells_corrupt_entries_from_good_ones() {
+        let dir = scratch_dir("verify", &["in/a.txt", "out/built.txt"]);
+        let (good, corrupt) = (config_for(&dir, "true"), config_for(&dir, "false"));
+        let mut inventory = Inventory::open(&good).unwrap();
+        let good_key = compute_key(&good).unwrap();
+        store(&mut inventory, &good, &good_key, None, None).unwrap();
+        // Other contents, so that the entries do not share a blob
+        std::fs::write(dir.join("out/built.txt"), "rebuilt").unwrap();
+        let corrupt_key = compute_key(&corrupt).unwrap();
+        store(&mut inventory, &corrupt, &corrupt_key, None, None).unwrap();
+
+        let value = inventory.inv[&corrupt_key];
+        let corrupt_path = inventory.to_path(&corrupt_key, value.compression);
+        let mut bytes = std::fs::read(&corrupt_path).unwrap();
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 0xff;
+        std::fs::write(&corrupt_path, bytes).unwrap();
+
+        let report = inventory.verify(false).unwrap();
+        assert_eq!(report.checked, 2);
+        assert_eq!(
+            report
+                .corrupt
+                .iter()
+                .map(|(path, _)| path)
+                .collect::<Vec<_>>(),
+            [&corrupt_path]
+        );
+        assert!(report.unchecked.is_empty());
+        assert!(corrupt_path.exists());
+
+        inventory.verify(true).unwrap();
+        let report = inventory.verify(false).unwrap();
+        assert_eq!((report.checked, report.corrupt.len()), (1, 0));
+        assert!(!corrupt_path.exists());
+        assert!(inventory.inv.contains_key(&good_key));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hits_order_eviction_by_the_injected_clock() {
+        let dir = scratch_dir("fake-clock", &["in/a.txt", "out/built.txt"]);
+        let config = |command: &str| config_with(&dir, &["--max-entries", "3"], command);
+        let secs = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+        let at = |at: u64| {
+            secs.store(at, std::sync::atomic::Ordering::SeqCst);
+            SystemTime::UNIX_EPOCH + Duration::from_secs(at)
+        };
+        let clock = secs.clone();
+        let mut inventory = Inventory::open(&config("first"))
+            .unwrap()
+            .with_clock(move || {
+                let secs = clock.load(std::sync::atomic::Ordering::SeqCst);
+                SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+            });
+        let mut keys = BTreeMap::new();
+        for (command, stored_at) in [("first", 100), ("second", 200), ("third", 300)] {
+            at(stored_at);
+            let key = compute_key(&config(command)).unwrap();
+            store(&mut inventory, &config(command), &key, None, None).unwrap();
+            keys.insert(command, key);
+        }
+
+        // The first entry is now the most recently used, the second the least
+        let hit_at = at(400);
+        assert!(
+            try_restore(&mut inventory, &config("first"), &keys["first"], None)
+                .unwrap()
+                .is_some()
+        );
+        assert_eq!(inventory.inv[&keys["first"]].last_used, hit_at);
+        at(500);
+        assert!(
+            try_restore(&mut inventory, &config("third"), &keys["third"], None)
+                .unwrap()
+                .is_some()
+        );
+        at(600);
+        let fourth = compute_key(&config("fourth")).unwrap();
+        store(&mut inventory, &config("fourth"), &fourth, None, None).unwrap();
+
+        // Read back from disk, so the hits must have been written there
+        let reopened = Inventory::open(&config("first")).unwrap();
+        let kept = |command: &str| reopened.inv.contains_key(&keys[command]);
+        assert!(!kept("second"));
+        assert!(kept("first") && kept("third") && reopened.inv.contains_key(&fourth));
+        assert_eq!(reopened.inv[&keys["first"]].last_used, hit_at);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn loads_read_each_shard_directory_once() {
+        let dir = scratch_dir("many-shards", &["in/a.txt", "out/built.txt"]);
+        let config = config_with(&dir, &["--shard", "--shard-inputs"], "true");
+        let mut inventory = Inventory::open(&config).unwrap();
+        let key = compute_key(&config).unwrap();
+        store(&mut inventory, &config, &key, None, None).unwrap();
+        let compression = inventory.inv[&key].compression;
+        let archive = inventory.to_path(&key, compression);
+
+        // Copies of one archive under many commands and inputs, spread over every shard
+        for i in 0..4000u64 {
+            let copy = CommandInputHashes {
+                command_hash: i.wrapping_mul(0x9e37_79b9_7f4a_7c15),
+                input_hash: i.wrapping_mul(0xc2b2_ae3d_27d4_eb4f),
+            };
+            let path = inventory.to_path(&copy, compression);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            if !path.exists() {
+                std::fs::hard_link(&archive, path).unwrap();
+            }
+        }
+        std::fs::remove_file(dir.join("cache/index.json")).unwrap();
+
+        let mut reads = HashMap::new();
+        let names = inventory
+            .local
+            .list_reading(|dir| *reads.entry(dir.to_path_buf()).or_insert(0) += 1)
+            .unwrap();
+        assert_eq!(names.len(), 4001);
+        assert!(reads.values().all(|&count| count == 1), "{:?}", reads);
+        for name in &names {
+            for shard in Path::new(name).ancestors().skip(1) {
+                assert!(
+                    reads.contains_key(&dir.join("cache").join(shard)),
+                    "{}",
+                    name
+                );
+            }
+        }
+
+        let reloaded = Inventory::open(&config).unwrap();
+        assert_eq!(reloaded.inv.len(), 4001);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,2735 @@
+//! Runs the folca binary on scratch directories
+
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Directory of a single test, removed when dropped
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("folca-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    fn path(&self, relative: &str) -> PathBuf {
+        self.0.join(relative)
+    }
+
+    /// Writes a file, creating its parent directories
+    fn write(&self, relative: &str, contents: &str) {
+        let path = self.path(relative);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    fn read(&self, relative: &str) -> String {
+        std::fs::read_to_string(self.path(relative)).unwrap()
+    }
+
+    /// How many times `COPY` ran
+    fn runs(&self) -> usize {
+        std::fs::read_to_string(self.path("runs")).map_or(0, |runs| runs.lines().count())
+    }
+
+    /// Archives of the entries in the default cache, blobs they share aside
+    fn archives(&self) -> Vec<PathBuf> {
+        self.archives_in(".folca_cache")
+    }
+
+    /// Archives of the entries in the cache at `cache`, blobs they share aside
+    fn archives_in(&self, cache: &str) -> Vec<PathBuf> {
+        fn walk(dir: &Path, archives: &mut Vec<PathBuf>) {
+            for entry in std::fs::read_dir(dir).into_iter().flatten() {
+                let path = entry.unwrap().path();
+                if path.is_dir() && path.file_name().unwrap() != "blobs" {
+                    walk(&path, archives);
+                } else if path.to_string_lossy().contains(".tar") {
+                    archives.push(path);
+                }
+            }
+        }
+        let mut archives = Vec::new();
+        walk(&self.path(cache), &mut archives);
+        archives.sort();
+        archives
+    }
+
+    /// folca run from the scratch directory, away from the environment of the test
+    fn folca<I, S>(&self, args: I) -> Command
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<std::ffi::OsStr>,
+    {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_folca"));
+        command
+            .current_dir(&self.0)
+            .env_remove("FOLCA_CACHE")
+            .args(args);
+        command
+    }
+
+    /// Output of a folca run, which must succeed
+    fn run<I, S>(&self, args: I) -> Output
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<std::ffi::OsStr>,
+    {
+        let output = self.folca(args).output().unwrap();
+        assert!(
+            output.status.success(),
+            "folca failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        output
+    }
+
+    /// Key printed by `--print-key` for the given arguments
+    fn key(&self, args: &[&str]) -> String {
+        let output = self.run(std::iter::once("--print-key").chain(args.iter().copied()));
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+
+    /// Whether the default cache holds the entry printed as `key` by `--print-key`
+    fn has_entry(&self, key: &str) -> bool {
+        let input_hash = key.split('/').nth(1).unwrap();
+        self.archives().iter().any(|archive| {
+            archive
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .starts_with(input_hash)
+        })
+    }
+
+    /// Index of the default cache
+    fn index(&self) -> serde_json::Value {
+        serde_json::from_slice(&std::fs::read(self.path(".folca_cache/index.json")).unwrap())
+            .unwrap()
+    }
+
+    /// When the entry printed as `key` by `--print-key` was last used, according to the index
+    fn last_used(&self, key: &str) -> SystemTime {
+        let index = self.index();
+        let last_used = &index_entry(&index, key)["last_used"];
+        SystemTime::UNIX_EPOCH
+            + Duration::new(
+                last_used["secs_since_epoch"].as_u64().unwrap(),
+                last_used["nanos_since_epoch"].as_u64().unwrap() as u32,
+            )
+    }
+
+    /// Makes the entry printed as `key` by `--print-key` look last used `age` ago
+    fn backdate(&self, key: &str, age: Duration) {
+        let mut index = self.index();
+        let since_epoch = (SystemTime::now() - age)
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap();
+        let entry = index["entries"]
+            .as_array_mut()
+            .unwrap()
+            .iter_mut()
+            .find(|entry| is_entry(entry, key))
+            .expect("no such entry");
+        entry["last_used"] = serde_json::json!({
+            "secs_since_epoch": since_epoch.as_secs(),
+            "nanos_since_epoch": since_epoch.subsec_nanos(),
+        });
+        std::fs::write(
+            self.path(".folca_cache/index.json"),
+            serde_json::to_vec(&index).unwrap(),
+        )
+        .unwrap();
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Text of `count` words drawn from a few, which compresses well but not trivially
+fn words(count: usize) -> String {
+    const WORDS: [&str; 8] = ["the", "quick", "brown", "fox", "jumps", "over", "a", "dog"];
+    let mut state = 1u32;
+    let mut text = String::new();
+    for _ in 0..count {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        text.push_str(WORDS[(state >> 16) as usize % WORDS.len()]);
+        text.push(' ');
+    }
+    text
+}
+
+const HOUR: Duration = Duration::from_secs(3600);
+
+/// Shell command copying `in` to `out`, counting its runs in `runs`
+const COPY: &str = "echo ran >> runs && rm -rf out && cp -r in out";
+
+/// Whether an entry of the index is the one printed as `key` by `--print-key`
+fn is_entry(entry: &serde_json::Value, key: &str) -> bool {
+    let (command_hash, input_hash) = key.split_once('/').unwrap();
+    entry["command_hash"] == u64::from_str_radix(command_hash, 16).unwrap()
+        && entry["input_hash"] == u64::from_str_radix(input_hash, 16).unwrap()
+}
+
+fn index_entry<'a>(index: &'a serde_json::Value, key: &str) -> &'a serde_json::Value {
+    index["entries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|entry| is_entry(entry, key))
+        .expect("no such entry")
+}
+
+/// Everything a run printed, as warnings go to stdout
+fn logs(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned() + &String::from_utf8_lossy(&output.stderr)
+}
+
+/// Error of a run given `0` for `option`, which must be refused
+fn zero_refused(scratch: &Scratch, option: &str) -> String {
+    let output = scratch
+        .folca([option, "0", "in", "out", "--", "true"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success(), "{} 0 was accepted", option);
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+/// Flips a byte in the middle of a file, which may be read-only
+fn corrupt(path: &Path) {
+    let mut permissions = path.metadata().unwrap().permissions();
+    #[allow(clippy::permissions_set_readonly_false)]
+    permissions.set_readonly(false);
+    std::fs::set_permissions(path, permissions).unwrap();
+    let mut bytes = std::fs::read(path).unwrap();
+    let middle = bytes.len() / 2;
+    bytes[middle] ^= 0xff;
+    std::fs::write(path, bytes).unwrap();
+}
+
+/// Every file under `dir`, along with its contents
+fn snapshot(dir: &Path) -> std::collections::BTreeMap<PathBuf, Vec<u8>> {
+    let mut files = std::collections::BTreeMap::new();
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            files.extend(snapshot(&path));
+        } else {
+            files.insert(path.clone(), std::fs::read(path).unwrap());
+        }
+    }
+    files
+}
+
+/// HTTP server keeping what is put to it in memory, standing in for remote caches
+struct MockServer {
+    url: String,
+    objects: Arc<Mutex<BTreeMap<String, Vec<u8>>>>,
+}
+
+impl MockServer {
+    fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let objects = Arc::new(Mutex::new(BTreeMap::new()));
+        let served = Arc::clone(&objects);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let _ = Self::serve(stream.unwrap(), &served);
+            }
+        });
+        Self { url, objects }
+    }
+
+    /// Paths of the objects put so far
+    fn paths(&self) -> Vec<String> {
+        self.objects.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Answers a single request, closing the connection afterwards
+    fn serve(stream: TcpStream, objects: &Mutex<BTreeMap<String, Vec<u8>>>) -> std::io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        let mut words = request_line.split_whitespace();
+        let method = words.next().unwrap_or_default().to_string();
+        let target = words.next().unwrap_or_default();
+        let path = target.split('?').next().unwrap().to_string();
+        let query = target.split_once('?').map_or("", |(_, query)| query);
+
+        let mut headers = BTreeMap::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            match line.trim_end().split_once(':') {
+                Some((name, value)) => {
+                    headers.insert(name.to_ascii_lowercase(), value.trim().to_string());
+                }
+                None => break,
+            }
+        }
+        let header = |name: &str| headers.get(name).map_or("", String::as_str);
+        let mut body = if header("transfer-encoding").contains("chunked") {
+            Self::dechunk(&mut reader)?
+        } else {
+            let length = header("content-length").parse().unwrap_or(0);
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body)?;
+            body
+        };
+        // S3 clients can frame the payload again to send checksums after it
+        if header("content-encoding").contains("aws-chunked") {
+            body = Self::dechunk(&mut &body[..])?;
+        }
+
+        let mut objects = objects.lock().unwrap();
+        let (status, response) = match method.as_str() {
+            "PUT" => {
+                objects.insert(path, body);
+                ("200 OK", Vec::new())
+            }
+            "DELETE" => {
+                objects.remove(&path);
+                ("204 No Content", Vec::new())
+            }
+            "GET" if !query.contains("list-type") => match objects.get(&path) {
+                Some(object) => ("200 OK", object.clone()),
+                None => (
+                    "404 Not Found",
+                    b"<Error><Code>NoSuchKey</Code><Message>No such key</Message></Error>".to_vec(),
+                ),
+            },
+            _ => ("501 Not Implemented", Vec::new()),
+        };
+        drop(objects);
+        let mut stream = stream;
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            response.len()
+        )?;
+        stream.write_all(&response)?;
+        stream.flush()
+    }
+
+    /// Reads a chunked body up to and including its trailers
+    fn dechunk(reader: &mut impl BufRead) -> std::io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            let size = line.split(';').next().unwrap().trim();
+            let size = usize::from_str_radix(size, 16).unwrap_or(0);
+            if size == 0 {
+                break;
+            }
+            let start = body.len();
+            body.resize(start + size, 0);
+            reader.read_exact(&mut body[start..])?;
+            reader.read_line(&mut line)?;
+        }
+        loop {
+            let mut trailer = String::new();
+            if reader.read_line(&mut trailer)? == 0 || trailer.trim().is_empty() {
+                return Ok(body);
+            }
+        }
+    }
+}
+
+fn copy_tree(from: &Path, to: &Path) {
+    std::fs::create_dir_all(to).unwrap();
+    for entry in std::fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        let target = to.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            copy_tree(&entry.path(), &target);
+        } else {
+            std::fs::copy(entry.path(), target).unwrap();
+        }
+    }
+}
+
+#[test]
+fn blake3_keys_of_identical_trees_match_across_processes() {
+    let scratch = Scratch::new("blake3-identical");
+    scratch.write("a/src/main.c", "int main() {}\n");
+    scratch.write("a/README", "hello\n");
+    copy_tree(&scratch.path("a"), &scratch.path("b"));
+
+    let args = |input| ["--hash-algo", "blake3", input, "out", "--", "make"];
+    let first = scratch.key(&args("a"));
+    assert_eq!(first, scratch.key(&args("a")));
+    assert_eq!(first, scratch.key(&args("b")));
+    assert_ne!(first, scratch.key(&["a", "out", "--", "make"]));
+}
+
+#[test]
+fn keys_of_a_known_tree_do_not_change() {
+    let scratch = Scratch::new("known-key");
+    scratch.write("in/hello.txt", "hello\n");
+    scratch.write("in/sub/world.txt", "world\n");
+
+    // Caches are shared between machines and folca versions, changing these misses all of them
+    assert_eq!(
+        scratch.key(&["in", "out", "--", "echo", "hi"]),
+        "bb57bb245e737b17/f72f35a003e9e114"
+    );
+    assert_eq!(
+        scratch.key(&["--hash-algo", "blake3", "in", "out", "--", "echo", "hi"]),
+        "3df20f2273968e02/5cea909217f1dc2e"
+    );
+}
+
+#[test]
+fn hidden_files_are_only_hashed_with_include_hidden() {
+    let scratch = Scratch::new("include-hidden");
+    scratch.write("in/main.c", "int main() {}\n");
+    scratch.write("in/.env", "A=1\n");
+    let default = ["in", "out", "--", "make"];
+    let hidden = ["--include-hidden", "in", "out", "--", "make"];
+    let before = (scratch.key(&default), scratch.key(&hidden));
+
+    scratch.write("in/.env", "A=2\n");
+    assert_eq!(scratch.key(&default), before.0);
+    assert_ne!(scratch.key(&hidden), before.1);
+}
+
+#[test]
+fn ignore_files_are_only_honoured_with_respect_ignore() {
+    let scratch = Scratch::new("respect-ignore");
+    scratch.write("in/main.c", "int main() {}\n");
+    scratch.write("in/.gitignore", "build/\n");
+    scratch.write("in/.ignore", "*.log\n");
+    scratch.write("in/build/main.o", "1");
+    scratch.write("in/make.log", "1");
+    let default = ["in", "out", "--", "make"];
+    let respect = ["--respect-ignore", "in", "out", "--", "make"];
+    let before = (scratch.key(&default), scratch.key(&respect));
+
+    scratch.write("in/build/main.o", "2");
+    scratch.write("in/make.log", "2");
+    assert_ne!(scratch.key(&default), before.0);
+    assert_eq!(scratch.key(&respect), before.1);
+}
+
+#[test]
+fn every_codec_round_trips() {
+    for codec in ["none", "gzip", "zstd"] {
+        let scratch = Scratch::new(&format!("round-trip-{}", codec));
+        scratch.write("in/a.txt", "alpha\n");
+        scratch.write("in/sub/b.txt", "beta\n");
+        let args = ["--compression", codec, "--shell", "in", "out", "--", COPY];
+        scratch.run(args);
+        std::fs::remove_dir_all(scratch.path("out")).unwrap();
+
+        scratch.run(args);
+        assert_eq!(scratch.runs(), 1, "{} entry was not restored", codec);
+        assert_eq!(scratch.read("out/a.txt"), "alpha\n");
+        assert_eq!(scratch.read("out/sub/b.txt"), "beta\n");
+        let archives = scratch.archives();
+        assert_eq!(archives.len(), 1);
+        let extension = match codec {
+            "none" => ".tar",
+            "gzip" => ".tar.gz",
+            _ => ".tar.zst",
+        };
+        assert!(archives[0].to_string_lossy().ends_with(extension));
+    }
+}
+
+#[test]
+fn compression_level_is_validated() {
+    let scratch = Scratch::new("level-range");
+    scratch.write("in/a.txt", "a");
+    let output = scratch
+        .folca(["--compression-level", "10", "in", "out", "--", "true"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("Compression level must be between 0 and 9"));
+}
+
+#[test]
+fn higher_compression_levels_write_smaller_archives() {
+    let text = words(60_000);
+    for codec in ["gzip", "zstd"] {
+        let size = |level: &str| {
+            let scratch = Scratch::new(&format!("level-{}-{}", codec, level));
+            scratch.write("in/a.txt", &text);
+            scratch.run([
+                "--compression",
+                codec,
+                "--compression-level",
+                level,
+                "--shell",
+                "in",
+                "out",
+                "--",
+                COPY,
+            ]);
+            scratch.archives()[0].metadata().unwrap().len()
+        };
+        assert!(size("9") <= size("1"), "{} level 9 is larger", codec);
+    }
+}
+
+#[test]
+fn parallel_gzip_decompresses_like_single_threaded() {
+    let scratch = Scratch::new("parallel-gzip");
+    scratch.write("in/a.txt", "a");
+    // Large enough to be compressed in several blocks
+    scratch.write("out/a.txt", &words(200_000));
+    let decompressed = |cache: &str, jobs: &str| {
+        scratch.run([
+            "--cache-path",
+            cache,
+            "--compression",
+            "gzip",
+            "--jobs",
+            jobs,
+            "in",
+            "out",
+            "--",
+            "true",
+        ]);
+        let archive = std::fs::File::open(&scratch.archives_in(cache)[0]).unwrap();
+        let mut bytes = Vec::new();
+        std::io::Read::read_to_end(&mut flate2::read::GzDecoder::new(archive), &mut bytes).unwrap();
+        bytes
+    };
+    assert_eq!(decompressed("single", "1"), decompressed("parallel", "4"));
+}
+
+#[test]
+fn clear_older_than_only_removes_entries_unused_since() {
+    let scratch = Scratch::new("clear-older-than");
+    let args = ["--shell", "in", "out", "--", COPY];
+    scratch.write("in/a.txt", "old");
+    scratch.run(args);
+    let old = scratch.key(&args);
+    scratch.write("in/a.txt", "new");
+    scratch.run(args);
+    let new = scratch.key(&args);
+    scratch.backdate(&old, 10 * 24 * HOUR);
+
+    let output = scratch.run(["clear", "--older-than", "7d"]);
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("Removed 1 entries"));
+    assert_eq!(scratch.archives().len(), 1);
+    assert!(scratch.has_entry(&new));
+
+    scratch.run(["clear"]);
+    assert!(scratch.archives().is_empty());
+    assert!(scratch.path(".folca_cache").is_dir());
+}
+
+#[test]
+fn corrupt_archives_are_dropped_and_the_command_rerun() {
+    let scratch = Scratch::new("corrupt-archive");
+    scratch.write("in/a.txt", &words(1000));
+    let args = ["--shell", "in", "out", "--", COPY];
+    scratch.run(args);
+    corrupt(&scratch.archives()[0]);
+
+    let output = scratch.run(args);
+    assert_eq!(scratch.runs(), 2);
+    assert!(logs(&output).contains("Discarding corrupt cache entry"));
+    // The entry was written again from the new run
+    scratch.run(args);
+    assert_eq!(scratch.runs(), 2);
+}
+
+#[test]
+fn interrupted_writes_are_never_loaded() {
+    let scratch = Scratch::new("interrupted-write");
+    scratch.write("in/a.txt", &words(1000));
+    let args = ["--shell", "in", "out", "--", COPY];
+    scratch.run(args);
+    // Leave only half of the archive, under the name it had while being written
+    let archive = scratch.archives().remove(0);
+    let bytes = std::fs::read(&archive).unwrap();
+    let mut temp_name = archive.file_name().unwrap().to_os_string();
+    temp_name.push(".tmp.99999");
+    std::fs::write(archive.with_file_name(temp_name), &bytes[..bytes.len() / 2]).unwrap();
+    std::fs::remove_file(&archive).unwrap();
+    std::fs::remove_file(scratch.path(".folca_cache/index.json")).unwrap();
+
+    let stats = scratch.run(["stats", "--json"]);
+    let stats: serde_json::Value = serde_json::from_slice(&stats.stdout).unwrap();
+    assert_eq!(stats["entries"], 0);
+    scratch.run(args);
+    assert_eq!(scratch.runs(), 2);
+}
+
+#[test]
+fn concurrent_runs_keep_each_others_entries() {
+    let scratch = Scratch::new("concurrent-writes");
+    let children: Vec<_> = (0..4)
+        .map(|i| {
+            let input = format!("in{}", i);
+            scratch.write(&format!("{}/a.txt", input), &words(20_000 + i));
+            scratch
+                .folca([
+                    "--lock-timeout",
+                    "30s",
+                    &input,
+                    &format!("out{}", i),
+                    "--shell",
+                    "--",
+                    &format!("sleep 0.2 && cp -r {} out{}", input, i),
+                ])
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    for mut child in children {
+        assert!(child.wait().unwrap().success());
+    }
+
+    let stats = scratch.run(["stats", "--json"]);
+    let stats: serde_json::Value = serde_json::from_slice(&stats.stdout).unwrap();
+    assert_eq!(stats["entries"], 4);
+    assert_eq!(scratch.archives().len(), 4);
+}
+
+#[test]
+fn eviction_follows_use_even_with_equal_atimes() {
+    let scratch = Scratch::new("noatime");
+    let args = ["--max-entries", "3", "--shell", "in", "out", "--", COPY];
+    let mut keys = Vec::new();
+    for input in ["a", "b", "c"] {
+        scratch.write("in/a.txt", input);
+        scratch.run(args);
+        keys.push(scratch.key(&args));
+    }
+    // Using the first entry makes the second the least recently used
+    scratch.write("in/a.txt", "a");
+    scratch.run(args);
+    assert_eq!(scratch.runs(), 3);
+    let atime = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+    for archive in scratch.archives() {
+        filetime::set_file_atime(archive, atime).unwrap();
+    }
+
+    scratch.write("in/a.txt", "d");
+    scratch.run(args);
+    assert!(scratch.has_entry(&keys[0]));
+    assert!(!scratch.has_entry(&keys[1]));
+    assert!(scratch.has_entry(&keys[2]));
+    assert_eq!(scratch.archives().len(), 3);
+}
+
+#[test]
+fn max_age_expires_stale_entries_only() {
+    let scratch = Scratch::new("max-age");
+    let args = ["--max-age", "7d", "--shell", "in", "out", "--", COPY];
+    scratch.write("in/a.txt", "stale");
+    scratch.run(args);
+    let stale = scratch.key(&args);
+    scratch.write("in/a.txt", "fresh");
+    scratch.run(args);
+    let fresh = scratch.key(&args);
+    scratch.backdate(&stale, 8 * 24 * HOUR);
+    scratch.backdate(&fresh, 6 * 24 * HOUR);
+
+    scratch.write("in/a.txt", "new");
+    scratch.run(args);
+    assert!(!scratch.has_entry(&stale));
+    assert!(scratch.has_entry(&fresh));
+    assert_eq!(scratch.archives().len(), 2);
+}
+
+#[test]
+fn max_entries_prunes_the_oldest() {
+    let scratch = Scratch::new("max-entries");
+    let args = ["--max-entries", "2", "--shell", "in", "out", "--", COPY];
+    let keys: Vec<_> = (0..5)
+        .map(|i| {
+            scratch.write("in/a.txt", &i.to_string());
+            scratch.run(args);
+            scratch.key(&args)
+        })
+        .collect();
+
+    assert_eq!(scratch.archives().len(), 2);
+    assert!(keys[..3].iter().all(|key| !scratch.has_entry(key)));
+    assert!(keys[3..].iter().all(|key| scratch.has_entry(key)));
+}
+
+#[test]
+fn cache_size_is_compared_to_archives_on_disk() {
+    let scratch = Scratch::new("compressed-size");
+    let args = [
+        "--max-cache-size",
+        "100KB",
+        "--shell",
+        "in",
+        "out",
+        "--",
+        "head -c 1000000 /dev/zero > out",
+    ];
+    for i in 0..3 {
+        scratch.write("in/a.txt", &i.to_string());
+        scratch.run(args);
+    }
+
+    // Each output is ten times the limit, but compresses to a fraction of it
+    let archives = scratch.archives();
+    assert_eq!(archives.len(), 3);
+    let on_disk: u64 = archives
+        .iter()
+        .map(|archive| archive.metadata().unwrap().len())
+        .sum();
+    assert!(on_disk < 100_000);
+}
+
+#[test]
+fn several_inputs_are_hashed_together_in_any_order() {
+    let scratch = Scratch::new("multiple-inputs");
+    scratch.write("a/x.txt", "x");
+    scratch.write("b/y.txt", "y");
+    let alone = |input| scratch.key(&[input, "out", "--", "make"]);
+    let together = |first, second| scratch.key(&["--input", second, first, "out", "--", "make"]);
+
+    let both = together("a", "b");
+    assert_ne!(both, alone("a"));
+    assert_ne!(both, alone("b"));
+    assert_eq!(both, together("b", "a"));
+}
+
+#[test]
+fn several_outputs_round_trip() {
+    let scratch = Scratch::new("multiple-outputs");
+    scratch.write("in/a.txt", "a");
+    let args = [
+        "--output",
+        "docs/html",
+        "--shell",
+        "in",
+        "build/bin",
+        "--",
+        "echo ran >> runs && mkdir -p build/bin docs/html \
+         && echo binary > build/bin/app && echo page > docs/html/index.html",
+    ];
+    scratch.run(args);
+    std::fs::remove_dir_all(scratch.path("build")).unwrap();
+    std::fs::remove_dir_all(scratch.path("docs")).unwrap();
+
+    scratch.run(args);
+    assert_eq!(scratch.runs(), 1);
+    assert_eq!(scratch.read("build/bin/app"), "binary\n");
+    assert_eq!(scratch.read("docs/html/index.html"), "page\n");
+}
+
+#[test]
+fn config_file_applies_unless_overridden() {
+    let scratch = Scratch::new("config-file");
+    scratch.write("in/a.txt", "a");
+    scratch.write(
+        ".folca.toml",
+        "cache_path = \"from-file\"\nhash_algo = \"blake3\"\n",
+    );
+    let args = ["--shell", "in", "out", "--", COPY];
+    scratch.run(args);
+    assert_eq!(scratch.read("from-file/.hash_algo").trim(), "blake3");
+
+    scratch.run(
+        ["--cache-path", "from-flag", "--hash-algo", "fnv1a"]
+            .iter()
+            .chain(&args),
+    );
+    assert_eq!(scratch.read("from-flag/.hash_algo").trim(), "fnv1a");
+}
+
+#[test]
+fn excluded_files_do_not_change_the_key() {
+    let scratch = Scratch::new("exclude");
+    scratch.write("in/main.c", "int main() {}\n");
+    scratch.write("in/debug.log", "1");
+    let args = ["--exclude", "*.log", "in", "out", "--", "make"];
+    let before = scratch.key(&args);
+
+    scratch.write("in/debug.log", "2");
+    assert_eq!(scratch.key(&args), before);
+    scratch.write("in/main.c", "int main() { return 1; }\n");
+    assert_ne!(scratch.key(&args), before);
+}
+
+#[test]
+fn only_included_files_change_the_key() {
+    let scratch = Scratch::new("include");
+    scratch.write("in/src/main.rs", "fn main() {}\n");
+    scratch.write("in/notes.txt", "1");
+    let args = ["--include", "**/*.rs", "in", "out", "--", "cargo"];
+    let before = scratch.key(&args);
+
+    scratch.write("in/notes.txt", "2");
+    assert_eq!(scratch.key(&args), before);
+    scratch.write("in/src/main.rs", "fn main() { panic!() }\n");
+    assert_ne!(scratch.key(&args), before);
+}
+
+#[test]
+fn unchanged_files_are_not_read_again_with_hash_cache() {
+    let scratch = Scratch::new("hash-cache");
+    let an_hour_ago = filetime::FileTime::from_system_time(SystemTime::now() - HOUR);
+    for i in 0..200 {
+        let file = format!("in/{}/{}.txt", i % 10, i);
+        scratch.write(&file, &words(200 + i));
+        // Files modified just before being hashed may change unnoticed, and are read every time
+        filetime::set_file_mtime(scratch.path(&file), an_hour_ago).unwrap();
+    }
+    let args = ["-vvv", "--hash-cache", "--shell", "in", "out", "--", COPY];
+    let first = logs(&scratch.run(args));
+    assert_eq!(first.matches("Hashed content of").count(), 200);
+
+    let second = logs(&scratch.run(args));
+    assert_eq!(scratch.runs(), 1);
+    assert_eq!(second.matches("Hashing content of").count(), 0);
+    assert_eq!(second.matches("Reusing hash of").count(), 200);
+}
+
+#[test]
+fn captured_output_is_replayed_exactly() {
+    let scratch = Scratch::new("capture");
+    scratch.write("in/a.txt", "a");
+    let args = [
+        "--capture",
+        "--shell",
+        "in",
+        "out",
+        "--",
+        "echo ran >> runs && printf 'line 1\\n\\ttabbed \\377\\n' && echo oops >&2 && cp -r in out",
+    ];
+    let first = scratch.run(args);
+    let second = scratch.run(args);
+    assert_eq!(scratch.runs(), 1);
+    assert_eq!(second.stdout, b"line 1\n\ttabbed \xff\n");
+    assert_eq!(second.stdout, first.stdout);
+    assert_eq!(second.stderr, b"oops\n");
+}
+
+#[test]
+fn commands_killed_by_a_signal_fail_the_run() {
+    let scratch = Scratch::new("killed");
+    scratch.write("in/a.txt", "a");
+    let status = scratch
+        .folca(["--shell", "in", "out", "--", "mkdir out && kill -TERM $$"])
+        .status()
+        .unwrap();
+    assert!(!status.success());
+    assert_eq!(status.code(), Some(128 + 15));
+    assert!(scratch.archives().is_empty());
+}
+
+#[test]
+fn force_reruns_cached_commands() {
+    let scratch = Scratch::new("force");
+    scratch.write("in/a.txt", "a");
+    let args = ["--shell", "in", "out", "--", COPY];
+    scratch.run(args);
+    let key = scratch.key(&args);
+    scratch.backdate(&key, HOUR);
+    let before = scratch.last_used(&key);
+
+    scratch.run(std::iter::once("--force").chain(args));
+    assert_eq!(scratch.runs(), 2);
+    assert!(scratch.last_used(&key) > before + HOUR / 2);
+    assert_eq!(scratch.archives().len(), 1);
+}
+
+#[test]
+fn read_only_misses_run_without_touching_the_cache() {
+    let scratch = Scratch::new("read-only");
+    let args = ["--shell", "in", "out", "--", COPY];
+    scratch.write("in/a.txt", "cached");
+    scratch.run(args);
+    let before = snapshot(&scratch.path(".folca_cache"));
+
+    scratch.write("in/a.txt", "new");
+    scratch.run(std::iter::once("--read-only").chain(args));
+    assert_eq!(scratch.runs(), 2);
+    assert_eq!(scratch.read("out/a.txt"), "new");
+    assert_eq!(snapshot(&scratch.path(".folca_cache")), before);
+}
+
+#[test]
+fn no_read_runs_and_overwrites_the_entry() {
+    let scratch = Scratch::new("no-read");
+    scratch.write("in/a.txt", "a");
+    // Each run leaves a different output, telling which one the entry holds
+    let args = [
+        "--shell",
+        "in",
+        "out",
+        "--",
+        "echo ran >> runs && mkdir -p out && cp runs out/runs",
+    ];
+    scratch.run(args);
+    scratch.run(std::iter::once("--no-read").chain(args));
+    assert_eq!(scratch.runs(), 2);
+
+    std::fs::remove_dir_all(scratch.path("out")).unwrap();
+    scratch.run(args);
+    assert_eq!(scratch.runs(), 2);
+    assert_eq!(scratch.read("out/runs"), "ran\nran\n");
+}
+
+#[test]
+fn explicit_keys_hit_whatever_the_inputs() {
+    let scratch = Scratch::new("explicit-key");
+    let args = ["--key", "release-1.2", "--shell", "in", "out", "--", COPY];
+    scratch.write("in/a.txt", "first");
+    scratch.run(args);
+    scratch.write("in/a.txt", "second");
+    scratch.write("in/b.txt", "added");
+    scratch.run(args);
+
+    assert_eq!(scratch.runs(), 1);
+    assert_eq!(scratch.read("out/a.txt"), "first");
+}
+
+#[test]
+fn lowercase_normalization_matches_trees_differing_in_case() {
+    let scratch = Scratch::new("lowercase");
+    scratch.write("a/Src/Main.c", "int main() {}\n");
+    scratch.write("b/src/main.c", "int main() {}\n");
+    let key = |input, normalization| {
+        scratch.key(&[
+            "--path-normalization",
+            normalization,
+            input,
+            "out",
+            "--",
+            "make",
+        ])
+    };
+
+    assert_ne!(key("a", "portable"), key("b", "portable"));
+    assert_eq!(key("a", "lowercase"), key("b", "lowercase"));
+}
+
+#[test]
+fn copies_of_a_tree_at_other_locations_share_keys() {
+    let scratch = Scratch::new("relocated");
+    scratch.write("first/src/main.c", "int main() {}\n");
+    copy_tree(&scratch.path("first"), &scratch.path("elsewhere/second"));
+    let key = |input: PathBuf| scratch.key(&[input.to_str().unwrap(), "out", "--", "make"]);
+
+    assert_eq!(
+        key(scratch.path("first")),
+        key(scratch.path("elsewhere/second"))
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn execute_bits_only_change_the_key_with_hash_mode() {
+    use std::os::unix::fs::PermissionsExt;
+    let scratch = Scratch::new("hash-mode");
+    scratch.write("in/build.sh", "echo hi\n");
+    let default = ["in", "out", "--", "make"];
+    let with_mode = ["--hash-mode", "in", "out", "--", "make"];
+    let before = (scratch.key(&default), scratch.key(&with_mode));
+
+    let script = scratch.path("in/build.sh");
+    let mut permissions = script.metadata().unwrap().permissions();
+    permissions.set_mode(permissions.mode() | 0o111);
+    std::fs::set_permissions(script, permissions).unwrap();
+    assert_eq!(scratch.key(&default), before.0);
+    assert_ne!(scratch.key(&with_mode), before.1);
+}
+
+#[cfg(unix)]
+#[test]
+fn outside_and_cyclic_symlinks_give_stable_keys() {
+    use std::os::unix::fs::symlink;
+    let scratch = Scratch::new("symlinks");
+    scratch.write("outside/secret.txt", "s");
+    scratch.write("copy/secret.txt", "s");
+    scratch.write("in/sub/a.txt", "a");
+    symlink(scratch.path("outside"), scratch.path("in/outside")).unwrap();
+    symlink("..", scratch.path("in/sub/cycle")).unwrap();
+
+    // Unfollowed links are hashed by their target path, not by what is behind them
+    let args = ["in", "out", "--", "make"];
+    let key = scratch.key(&args);
+    assert_eq!(scratch.key(&args), key);
+    scratch.write("outside/secret.txt", "changed");
+    assert_eq!(scratch.key(&args), key);
+    std::fs::remove_file(scratch.path("in/outside")).unwrap();
+    symlink(scratch.path("copy"), scratch.path("in/outside")).unwrap();
+    assert_ne!(scratch.key(&args), key);
+    std::fs::remove_file(scratch.path("in/outside")).unwrap();
+    symlink(scratch.path("outside"), scratch.path("in/outside")).unwrap();
+    assert_eq!(scratch.key(&args), key);
+
+    // Followed, the cycle is skipped with a warning, which goes to stdout before the key
+    let followed = || {
+        let output = scratch
+            .folca([
+                "--print-key",
+                "--follow-symlinks",
+                "in",
+                "out",
+                "--",
+                "make",
+            ])
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "folca failed: {}", logs(&output));
+        assert!(
+            logs(&output).contains("Skipping symlink loop"),
+            "{}",
+            logs(&output)
+        );
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        stdout.lines().last().unwrap().to_string()
+    };
+    let key = followed();
+    assert_eq!(followed(), key);
+    scratch.write("outside/secret.txt", "changed again");
+    assert_ne!(followed(), key);
+}
+
+#[cfg(unix)]
+#[test]
+fn symlinks_in_outputs_are_restored_as_symlinks() {
+    let scratch = Scratch::new("output-symlink");
+    scratch.write("in/a.txt", "a");
+    let args = [
+        "--shell",
+        "in",
+        "out",
+        "--",
+        "echo ran >> runs && mkdir out && echo lib > out/lib.so.1 && ln -s lib.so.1 out/lib.so",
+    ];
+    scratch.run(args);
+    std::fs::remove_dir_all(scratch.path("out")).unwrap();
+
+    scratch.run(args);
+    assert_eq!(scratch.runs(), 1);
+    let link = scratch.path("out/lib.so");
+    assert!(link.symlink_metadata().unwrap().file_type().is_symlink());
+    assert_eq!(std::fs::read_link(link).unwrap(), Path::new("lib.so.1"));
+}
+
+#[test]
+fn empty_output_directories_are_restored() {
+    let scratch = Scratch::new("empty-subdir");
+    scratch.write("in/a.txt", "a");
+    let args = [
+        "--shell",
+        "in",
+        "out",
+        "--",
+        "echo ran >> runs && mkdir -p out/empty_subdir && echo a > out/a.txt",
+    ];
+    scratch.run(args);
+    std::fs::remove_dir_all(scratch.path("out")).unwrap();
+
+    scratch.run(args);
+    assert_eq!(scratch.runs(), 1);
+    assert!(scratch.path("out/empty_subdir").is_dir());
+}
+
+#[test]
+fn file_outputs_are_restored_to_exactly_the_output_path() {
+    let scratch = Scratch::new("file-output");
+    scratch.write("in/a.txt", "a");
+    let args = [
+        "--shell",
+        "in",
+        "out.txt",
+        "--",
+        "echo ran >> runs && echo built > out.txt",
+    ];
+    scratch.run(args);
+
+    std::fs::remove_file(scratch.path("out.txt")).unwrap();
+    scratch.run(args);
+    assert_eq!(scratch.runs(), 1);
+    assert!(scratch.path("out.txt").is_file());
+    assert_eq!(scratch.read("out.txt"), "built\n");
+
+    scratch.write("out.txt", "stale and longer than the cached output\n");
+    scratch.run(args);
+    assert_eq!(scratch.runs(), 1);
+    assert_eq!(scratch.read("out.txt"), "built\n");
+}
+
+#[test]
+fn outputs_are_restored_under_missing_parents() {
+    let scratch = Scratch::new("missing-parent");
+    scratch.write("in/a.txt", "a");
+    let args = [
+        "--shell",
+        "in",
+        "nested/dir/out.txt",
+        "--",
+        "echo ran >> runs && mkdir -p nested/dir && echo built > nested/dir/out.txt",
+    ];
+    scratch.run(args);
+    std::fs::remove_dir_all(scratch.path("nested")).unwrap();
+
+    scratch.run(args);
+    assert_eq!(scratch.runs(), 1);
+    assert_eq!(scratch.read("nested/dir/out.txt"), "built\n");
+}
+
+#[test]
+fn missing_outputs_are_an_error_and_not_cached() {
+    let scratch = Scratch::new("missing-output");
+    scratch.write("in/a.txt", "a");
+    let output = scratch
+        .folca(["in", "nested/out", "--", "true"])
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    assert!(logs(&output).contains("did not produce its output"));
+    assert!(scratch.archives().is_empty());
+    assert!(!scratch.path("nested").exists());
+}
+
+#[test]
+fn remote_caches_are_shared_between_machines() {
+    let remote = MockServer::start();
+    let first = Scratch::new("remote-first");
+    let second = Scratch::new("remote-second");
+    for scratch in [&first, &second] {
+        scratch.write("in/hello.txt", "hello");
+        scratch.write("in/sub/world.txt", "world");
+    }
+    let args = [
+        "--remote-url",
+        &remote.url,
+        "--shell",
+        "in",
+        "out",
+        "--",
+        COPY,
+    ];
+
+    first.run(args);
+    assert_eq!(first.runs(), 1);
+    assert!(remote.paths().iter().any(|path| path.contains(".tar")));
+
+    second.run(args);
+    assert_eq!(second.runs(), 0);
+    assert_eq!(second.read("out/hello.txt"), "hello");
+    assert_eq!(second.read("out/sub/world.txt"), "world");
+    assert_eq!(second.archives().len(), 1);
+}
+
+#[cfg(feature = "s3")]
+#[test]
+fn s3_caches_are_shared_between_machines() {
+    let endpoint = MockServer::start();
+    let first = Scratch::new("s3-first");
+    let second = Scratch::new("s3-second");
+    for scratch in [&first, &second] {
+        scratch.write("in/hello.txt", "hello");
+    }
+    let args = [
+        "--s3-bucket",
+        "bucket",
+        "--s3-prefix",
+        "ci/",
+        "--shell",
+        "in",
+        "out",
+        "--",
+        COPY,
+    ];
+    let run = |scratch: &Scratch| {
+        let output = scratch
+            .folca(args)
+            .env("AWS_ENDPOINT_URL", &endpoint.url)
+            .env("AWS_ACCESS_KEY_ID", "folca")
+            .env("AWS_SECRET_ACCESS_KEY", "folca")
+            .env("AWS_REGION", "us-east-1")
+            .env("AWS_CONFIG_FILE", scratch.path("aws-config"))
+            .env(
+                "AWS_SHARED_CREDENTIALS_FILE",
+                scratch.path("aws-credentials"),
+            )
+            .env("AWS_EC2_METADATA_DISABLED", "true")
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "folca failed: {}", logs(&output));
+        logs(&output)
+    };
+
+    let logs = run(&first);
+    assert_eq!(first.runs(), 1);
+    assert!(!logs.contains("remote cache"), "{}", logs);
+    let paths = endpoint.paths();
+    assert!(
+        paths
+            .iter()
+            .any(|path| path.starts_with("/bucket/ci/") && path.contains(".tar")),
+        "{:?}",
+        paths
+    );
+
+    run(&second);
+    assert_eq!(second.runs(), 0);
+    assert_eq!(second.read("out/hello.txt"), "hello");
+}
+
+#[test]
+fn json_logs_describe_hits() {
+    let scratch = Scratch::new("json-logs");
+    scratch.write("in/a.txt", "a");
+    let args = ["--shell", "in", "out", "--", COPY];
+    scratch.run(args);
+    let key = scratch.key(&args);
+
+    let output = scratch.run(["-v", "--log-format", "json"].iter().chain(&args));
+    let lines: Vec<serde_json::Value> = String::from_utf8(output.stderr)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("not a JSON line"))
+        .collect();
+    let hit = lines
+        .iter()
+        .find(|line| line["hit"] == true)
+        .expect("no line for the hit");
+    assert_eq!(hit["level"], "INFO");
+    assert!(hit["timestamp"].is_string());
+    assert!(hit["message"]
+        .as_str()
+        .unwrap()
+        .contains("Found cached entry"));
+    assert_eq!(
+        format!(
+            "{}/{}",
+            hit["command_hash"].as_str().unwrap(),
+            hit["input_hash"].as_str().unwrap()
+        ),
+        key
+    );
+    assert_eq!(
+        hit["archive_size"].as_u64().unwrap(),
+        scratch.archives()[0].metadata().unwrap().len()
+    );
+}
+
+#[test]
+fn reports_tell_hits_from_misses() {
+    let scratch = Scratch::new("report");
+    scratch.write("in/a.txt", "a");
+    let args = [
+        "--report",
+        "report.json",
+        "--shell",
+        "in",
+        "out",
+        "--",
+        COPY,
+    ];
+    let key = scratch.key(&args[2..]);
+    let (command_hash, input_hash) = key.split_once('/').unwrap();
+    let report =
+        || -> serde_json::Value { serde_json::from_str(&scratch.read("report.json")).unwrap() };
+
+    scratch.run(args);
+    let miss = report();
+    assert_eq!(miss["hit"], false);
+    assert_eq!(miss["command_hash"], command_hash);
+    assert_eq!(miss["input_hash"], input_hash);
+    assert_eq!(miss["exit_code"], 0);
+    let archive_size = scratch.archives()[0].metadata().unwrap().len();
+    assert_eq!(miss["archive_size"], archive_size);
+
+    scratch.run(args);
+    let hit = report();
+    assert_eq!(hit["hit"], true);
+    assert_eq!(hit["command_hash"], command_hash);
+    assert_eq!(hit["input_hash"], input_hash);
+    assert_eq!(hit["exit_code"], serde_json::Value::Null);
+    assert_eq!(hit["archive_size"], archive_size);
+}
+
+#[test]
+fn dry_runs_exit_with_whether_they_would_hit() {
+    let scratch = Scratch::new("dry-run");
+    scratch.write("in/a.txt", "a");
+    let args = ["--dry-run", "--shell", "in", "out", "--", COPY];
+    let key = scratch.key(&args[1..]);
+
+    let miss = scratch.folca(args).output().unwrap();
+    assert_eq!(miss.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&miss.stdout).contains(&format!("Cache miss for {}", key)));
+    assert_eq!(scratch.runs(), 0);
+    assert!(scratch.archives().is_empty());
+
+    scratch.run(&args[1..]);
+    let hit = scratch.folca(args).output().unwrap();
+    assert_eq!(hit.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&hit.stdout).contains(&format!("Cache hit for {}", key)));
+    assert_eq!(scratch.runs(), 1);
+}
+
+#[test]
+fn loads_read_the_index_and_forget_deleted_entries() {
+    let scratch = Scratch::new("index-load");
+    scratch.write("in/a.txt", "a");
+    let kept = ["--shell", "in", "out", "--", COPY];
+    let deleted = ["--shell", "in", "out", "--", "rm -rf out && cp -r in out"];
+    scratch.run(kept);
+    scratch.run(deleted);
+    let (kept, deleted) = (scratch.key(&kept), scratch.key(&deleted));
+
+    // Only the index knows about this, the archive itself is untouched
+    scratch.backdate(&kept, 2 * HOUR);
+    let last_used = humantime::format_rfc3339_seconds(scratch.last_used(&kept)).to_string();
+    for archive in scratch.archives() {
+        if archive
+            .to_string_lossy()
+            .contains(deleted.split('/').next().unwrap())
+        {
+            std::fs::remove_file(archive).unwrap();
+        }
+    }
+
+    let listing = String::from_utf8(scratch.run(["list"]).stdout).unwrap();
+    let lines: Vec<&str> = listing.lines().collect();
+    assert_eq!(lines.len(), 1, "{}", listing);
+    assert!(lines[0].starts_with(&kept.replace('/', " ")));
+    assert!(lines[0].ends_with(&last_used));
+    let index = scratch.index();
+    assert_eq!(index["entries"].as_array().unwrap().len(), 1);
+    index_entry(&index, &kept);
+}
+
+#[test]
+fn stray_files_are_told_apart_and_collected() {
+    let scratch = Scratch::new("gc");
+    scratch.write("in/a.txt", "a");
+    let args = ["--shell", "in", "out", "--", COPY];
+    scratch.run(args);
+    let key = scratch.key(&args);
+    let archive = scratch.archives().remove(0);
+    let command_dir = archive.parent().unwrap();
+    let temp = command_dir.join(format!(
+        "{}.tmp.12345",
+        archive.file_name().unwrap().to_string_lossy()
+    ));
+    std::fs::copy(&archive, &temp).unwrap();
+    let junk = [
+        scratch.path(".folca_cache/.DS_Store"),
+        command_dir.join("notes.txt"),
+    ];
+    for path in &junk {
+        std::fs::write(path, "junk").unwrap();
+    }
+    let misnamed = command_dir.join("not-a-key.tar.gz");
+    std::fs::write(&misnamed, "junk").unwrap();
+
+    // Rebuilding the index walks the cache, only files shaped like entries are worth a warning
+    let index = scratch.path(".folca_cache/index.json");
+    let saved_index = std::fs::read(&index).unwrap();
+    std::fs::remove_file(&index).unwrap();
+    let logs = logs(&scratch.run(["list"]));
+    let warnings: Vec<&str> = logs.lines().filter(|line| line.contains("WARN")).collect();
+    assert_eq!(warnings.len(), 1, "{}", logs);
+    assert!(warnings[0].contains("not-a-key.tar.gz"));
+    // A rebuilt index has no checksums to tell which blob is whose, put the original back
+    std::fs::write(&index, saved_index).unwrap();
+
+    let output = String::from_utf8(scratch.run(["--gc"]).stdout).unwrap();
+    assert!(output.contains("Removed 4 stray files"), "{}", output);
+    for path in junk.iter().chain([&temp, &misnamed]) {
+        assert!(!path.exists(), "{} was kept", path.display());
+    }
+    assert_eq!(scratch.archives(), [archive]);
+
+    std::fs::remove_dir_all(scratch.path("out")).unwrap();
+    scratch.run(args);
+    assert_eq!(scratch.runs(), 1);
+    assert!(scratch.has_entry(&key));
+}
+
+#[test]
+fn identical_outputs_share_a_blob() {
+    let scratch = Scratch::new("dedup");
+    scratch.write("in/a.txt", &words(1000));
+    // Preserving timestamps makes both commands give byte-identical archives
+    let first = ["--shell", "in", "out", "--", "rm -rf out && cp -rp in out"];
+    let second = ["--shell", "in", "out", "--", "rm -rf out; cp -rp in out"];
+    scratch.run(first);
+    scratch.run(second);
+    assert!(scratch.has_entry(&scratch.key(&first)));
+    assert!(scratch.has_entry(&scratch.key(&second)));
+    assert_eq!(scratch.archives().len(), 2);
+
+    let blobs: Vec<_> = std::fs::read_dir(scratch.path(".folca_cache/blobs"))
+        .unwrap()
+        .collect();
+    assert_eq!(blobs.len(), 1);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let blob = blobs[0].as_ref().unwrap().metadata().unwrap();
+        assert_eq!(blob.nlink(), 3);
+        for archive in scratch.archives() {
+            assert_eq!(archive.metadata().unwrap().ino(), blob.ino());
+        }
+    }
+}
+
+#[test]
+fn reproducible_archives_are_byte_identical() {
+    let scratch = Scratch::new("reproducible");
+    scratch.write("in/a.txt", &words(1000));
+    scratch.write("in/sub/b.txt", "b");
+    for compression in ["none", "gzip", "zstd"] {
+        let args = [
+            "--reproducible",
+            "--compression",
+            compression,
+            "--shell",
+            "in",
+            "out",
+            "--",
+            COPY,
+        ];
+        scratch.run(args);
+        let first = std::fs::read(&scratch.archives()[0]).unwrap();
+        // The copy made by the second write gets other modification times
+        std::thread::sleep(Duration::from_millis(1100));
+        scratch.run(["--force"].iter().chain(&args));
+        let second = std::fs::read(&scratch.archives()[0]).unwrap();
+        assert!(first == second, "{} archives differ", compression);
+        std::fs::remove_dir_all(scratch.path(".folca_cache")).unwrap();
+    }
+    assert_eq!(scratch.runs(), 6);
+}
+
+#[test]
+fn archives_list_outputs_in_lexicographic_order() {
+    let scratch = Scratch::new("tar-order");
+    scratch.write("in/a.txt", "a");
+    // Directories tend to list files in creation order, reverse it
+    let mut command = String::from("echo ran >> runs && rm -rf out && mkdir -p out/sub");
+    for name in ["z", "y", "sub/c", "m", "sub/b", "b", "a"] {
+        command.push_str(&format!(" && echo {0} > out/{0}", name));
+    }
+    scratch.run([
+        "--compression",
+        "none",
+        "--shell",
+        "in",
+        "out",
+        "--",
+        &command,
+    ]);
+
+    let mut archive = tar::Archive::new(std::fs::File::open(&scratch.archives()[0]).unwrap());
+    let paths: Vec<PathBuf> = archive
+        .entries()
+        .unwrap()
+        .map(|entry| entry.unwrap().path().unwrap().into_owned())
+        .filter(|path| path.starts_with("0"))
+        .collect();
+    let mut sorted = paths.clone();
+    sorted.sort();
+    assert_eq!(paths, sorted);
+    assert_eq!(paths.len(), 9);
+}
+
+#[test]
+fn failing_commands_say_so_and_cache_nothing() {
+    let scratch = Scratch::new("failing-command");
+    scratch.write("in/a.txt", "a");
+    let output = scratch
+        .folca(["--shell", "in", "out", "--", "exit 3"])
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(3));
+    assert!(
+        logs(&output).contains("Command exited with code 3, nothing was cached"),
+        "{}",
+        logs(&output)
+    );
+    assert!(scratch.archives().is_empty());
+}
+
+#[test]
+fn folcaignore_keeps_matching_files_out_of_the_key() {
+    let scratch = Scratch::new("folcaignore");
+    scratch.write("in/.folcaignore", "*.tmp\n");
+    scratch.write("in/a.txt", "a");
+    scratch.write("in/sub/b.txt", "b");
+    let args = ["in", "out", "--", "true"];
+    let key = scratch.key(&args);
+
+    scratch.write("in/editor.tmp", "swap");
+    scratch.write("in/sub/build.tmp", "partial");
+    assert_eq!(scratch.key(&args), key);
+    scratch.write("in/sub/build.tmp", "other partial");
+    assert_eq!(scratch.key(&args), key);
+
+    scratch.write("in/sub/b.txt", "changed");
+    assert_ne!(scratch.key(&args), key);
+}
+
+#[test]
+fn declared_environment_variables_are_part_of_the_key() {
+    let scratch = Scratch::new("env-key");
+    scratch.write("in/a.txt", "a");
+    let run = |args: &[&str], rustflags: &str| {
+        let output = scratch
+            .folca(args)
+            .env("RUSTFLAGS", rustflags)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "folca failed: {}", logs(&output));
+    };
+    let declared = ["--env-key", "RUSTFLAGS", "--shell", "in", "out", "--", COPY];
+    run(&declared, "-O");
+    run(&declared, "-O");
+    assert_eq!(scratch.runs(), 1);
+    run(&declared, "-g");
+    assert_eq!(scratch.runs(), 2);
+
+    let undeclared = &declared[2..];
+    run(undeclared, "-O");
+    run(undeclared, "-g");
+    assert_eq!(scratch.runs(), 3);
+}
+
+#[test]
+fn hash_cwd_makes_the_working_directory_part_of_the_key() {
+    let scratch = Scratch::new("hash-cwd");
+    scratch.write("in/a.txt", "a");
+    scratch.write("elsewhere/.keep", "");
+    let (input, output) = (scratch.path("in"), scratch.path("out"));
+    let key_from = |cwd: &str, hash_cwd: bool| {
+        let mut command = scratch.folca(["--print-key"]);
+        if hash_cwd {
+            command.arg("--hash-cwd");
+        }
+        let output = command
+            .args([&input, &output])
+            .args(["--", "true"])
+            .current_dir(scratch.path(cwd))
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "folca failed: {}", logs(&output));
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    assert_eq!(key_from(".", false), key_from("elsewhere", false));
+    assert_ne!(key_from(".", true), key_from("elsewhere", true));
+    assert_eq!(key_from("elsewhere", true), key_from("elsewhere", true));
+}
+
+#[test]
+fn missing_or_empty_inputs_run_uncached() {
+    let scratch = Scratch::new("empty-input");
+    std::fs::create_dir(scratch.path("empty")).unwrap();
+    let command = "echo ran >> runs && mkdir -p out";
+    for (input, warning) in [("missing", "Input path"), ("empty", "No input files found")] {
+        let runs = scratch.runs();
+        for _ in 0..2 {
+            let output = scratch.run(["--shell", input, "out", "--", command]);
+            assert!(logs(&output).contains(warning), "{}", logs(&output));
+            assert!(logs(&output).contains("--allow-empty-input"));
+        }
+        assert_eq!(scratch.runs(), runs + 2);
+        assert!(scratch.archives().is_empty());
+    }
+
+    let allowed = [
+        "--allow-empty-input",
+        "--shell",
+        "empty",
+        "out",
+        "--",
+        command,
+    ];
+    scratch.run(allowed);
+    scratch.run(allowed);
+    assert_eq!(scratch.runs(), 5);
+    assert_eq!(scratch.archives().len(), 1);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn caches_under_non_utf8_paths_are_recognized_and_evictable() {
+    use std::os::unix::ffi::OsStrExt;
+    let scratch = Scratch::new("non-utf8-cache");
+    scratch.write("in/a.txt", "a");
+    let cache = scratch
+        .0
+        .join(std::ffi::OsStr::from_bytes(b"caf\xe9-cache"));
+    let run = |command: &str, extra: &[&str]| {
+        let mut folca = scratch.folca(["--cache-path".as_ref(), cache.as_os_str()]);
+        folca
+            .args(extra)
+            .args(["--shell", "in", "out", "--", command]);
+        let output = folca.output().unwrap();
+        assert!(output.status.success(), "folca failed: {}", logs(&output));
+    };
+    let archives = || -> Vec<PathBuf> {
+        std::fs::read_dir(&cache)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.is_dir() && path.file_name().unwrap() != "blobs")
+            .flat_map(|dir| std::fs::read_dir(dir).unwrap())
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.to_string_lossy().ends_with(".tar.gz"))
+            .collect()
+    };
+
+    run(COPY, &[]);
+    // Found by walking the cache rather than through the index
+    std::fs::remove_file(cache.join("index.json")).unwrap();
+    run(COPY, &[]);
+    assert_eq!(scratch.runs(), 1);
+    let first = archives();
+    assert_eq!(first.len(), 1);
+
+    run("rm -rf out && cp -r in out", &["--max-entries", "1"]);
+    let second = archives();
+    assert_eq!(second.len(), 1);
+    assert_ne!(first, second);
+}
+
+#[test]
+fn sharded_entries_are_stored_and_reloaded() {
+    let scratch = Scratch::new("shard");
+    scratch.write("in/a.txt", "a");
+    let args = ["--shard", "--shell", "in", "out", "--", COPY];
+    scratch.run(args);
+    let key = scratch.key(&args);
+    let (command_hash, input_hash) = key.split_once('/').unwrap();
+    let archive = scratch.path(&format!(
+        ".folca_cache/{}/{}/{}.tar.gz",
+        &command_hash[..2],
+        command_hash,
+        input_hash
+    ));
+    assert_eq!(scratch.archives(), [archive]);
+
+    // Reloaded by walking the cache as well as from the index
+    for _ in 0..2 {
+        std::fs::remove_dir_all(scratch.path("out")).unwrap();
+        scratch.run(args);
+        assert_eq!(scratch.runs(), 1);
+        assert_eq!(scratch.read("out/a.txt"), "a");
+        std::fs::remove_file(scratch.path(".folca_cache/index.json")).unwrap();
+    }
+}
+
+#[test]
+fn many_sharded_entries_are_all_listed() {
+    let scratch = Scratch::new("many-shards");
+    scratch.write("in/a.txt", "a");
+    let sharding = ["--shard", "--shard-inputs"];
+    scratch.run(sharding.iter().chain(&["--shell", "in", "out", "--", COPY]));
+    let archive = scratch.archives().remove(0);
+
+    // Copies of one archive under many commands and inputs, spread over every shard
+    std::fs::remove_file(scratch.path(".folca_cache/index.json")).unwrap();
+    for i in 0..4000u64 {
+        let command_hash = i.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        let input_hash = format!("{:016x}", i.wrapping_mul(0xc2b2_ae3d_27d4_eb4f));
+        let dir = scratch.path(&format!(
+            ".folca_cache/{:02x}/{:x}/{}",
+            command_hash >> 56,
+            command_hash,
+            &input_hash[..2]
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!("{}.tar.gz", input_hash));
+        if !path.exists() {
+            std::fs::hard_link(&archive, path).unwrap();
+        }
+    }
+
+    let listing = String::from_utf8(scratch.run(sharding.iter().chain(&["list"])).stdout).unwrap();
+    // The original entry is listed as well
+    assert_eq!(listing.lines().count(), 4001);
+    assert_eq!(scratch.index()["entries"].as_array().unwrap().len(), 4001);
+}
+
+#[test]
+fn dry_runs_name_the_input_files_that_changed() {
+    let scratch = Scratch::new("dry-run-changes");
+    scratch.write("in/a.txt", "a");
+    scratch.write("in/sub/b.txt", "b");
+    scratch.write("in/c.txt", "c");
+    let args = ["--hash-cache", "--shell", "in", "out", "--", COPY];
+    scratch.run(args);
+
+    scratch.write("in/sub/b.txt", "changed");
+    let output = scratch
+        .folca(["-v", "--dry-run"].iter().chain(&args))
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let changes: Vec<String> = logs(&output)
+        .lines()
+        .filter(|line| line.contains("since the last cached run"))
+        .map(String::from)
+        .collect();
+    assert_eq!(changes.len(), 1, "{:?}", changes);
+    assert!(changes[0].contains("Changed since the last cached run"));
+    let changed = scratch.path("in/sub/b.txt");
+    assert!(changes[0].ends_with(&*changed.to_string_lossy()));
+}
+
+#[test]
+fn sampled_files_collide_on_middle_changes_only() {
+    let scratch = Scratch::new("sample-bytes");
+    let contents = "0123456789".repeat(10);
+    scratch.write("in/big.bin", &contents);
+    let sampled = ["--sample-bytes", "16", "in", "out", "--", "true"];
+    let full = &sampled[2..];
+    let (sampled_key, full_key) = (scratch.key(&sampled), scratch.key(full));
+
+    let mut middle = contents.clone().into_bytes();
+    middle[50] = b'x';
+    scratch.write("in/big.bin", std::str::from_utf8(&middle).unwrap());
+    // Documented collision: only the first and last 16 bytes and the size are hashed
+    assert_eq!(scratch.key(&sampled), sampled_key);
+    assert_ne!(scratch.key(full), full_key);
+
+    let mut longer = contents.clone();
+    longer.insert(50, 'x');
+    scratch.write("in/big.bin", &longer);
+    assert_ne!(scratch.key(&sampled), sampled_key);
+
+    let mut start = contents.into_bytes();
+    start[3] = b'x';
+    scratch.write("in/big.bin", std::str::from_utf8(&start).unwrap());
+    assert_ne!(scratch.key(&sampled), sampled_key);
+
+    let error = zero_refused(&scratch, "--sample-bytes");
+    assert!(
+        error.contains("'--sample-bytes <sample-bytes>': Size cannot be zero"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn parallel_hashing_gives_the_serial_key() {
+    let scratch = Scratch::new("parallel-hashing");
+    for i in 0..200 {
+        scratch.write(&format!("in/{}/{}.txt", i % 7, i), &words(i * 10));
+    }
+    let key_with_threads = |threads: &str| {
+        let output = scratch
+            .folca(["--print-key", "in", "out", "--", "true"])
+            .env("RAYON_NUM_THREADS", threads)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "folca failed: {}", logs(&output));
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    let serial = key_with_threads("1");
+    for threads in ["2", "8", "32"] {
+        assert_eq!(
+            key_with_threads(threads),
+            serial,
+            "with {} threads",
+            threads
+        );
+    }
+}
+
+#[test]
+fn read_buffer_size_does_not_change_the_key() {
+    let scratch = Scratch::new("read-buffer-size");
+    scratch.write("in/big.txt", &words(50_000));
+    scratch.write("in/small.txt", "small");
+    for hash_algo in ["fnv1a", "blake3"] {
+        let key_with_buffer = |size: &str| {
+            scratch.key(&[
+                "--hash-algo",
+                hash_algo,
+                "--read-buffer-size",
+                size,
+                "in",
+                "out",
+                "--",
+                "true",
+            ])
+        };
+        let key = key_with_buffer("1MiB");
+        // Chunk boundaries fall all over the files, down to every single byte
+        for size in ["1", "7", "4096", "65537"] {
+            assert_eq!(
+                key_with_buffer(size),
+                key,
+                "{} with {} byte buffers",
+                hash_algo,
+                size
+            );
+        }
+    }
+
+    let error = zero_refused(&scratch, "--read-buffer-size");
+    assert!(
+        error.contains("'--read-buffer-size <read-buffer-size>': Size cannot be zero"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn cache_path_defaults_to_the_environment_variable() {
+    let scratch = Scratch::new("cache-env");
+    scratch.write("in/a.txt", "a");
+    let run = |args: &[&str]| {
+        let output = scratch
+            .folca(args)
+            .env("FOLCA_CACHE", scratch.path("from-env"))
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "folca failed: {}", logs(&output));
+    };
+
+    run(&["--shell", "in", "out", "--", COPY]);
+    assert_eq!(scratch.archives_in("from-env").len(), 1);
+    assert!(!scratch.path(".folca_cache").exists());
+
+    run(&[
+        "--cache-path",
+        "from-flag",
+        "--shell",
+        "in",
+        "out",
+        "--",
+        COPY,
+    ]);
+    assert_eq!(scratch.archives_in("from-flag").len(), 1);
+    assert_eq!(scratch.runs(), 2);
+}
+
+#[test]
+fn lists_are_sorted_and_filtered_by_size() {
+    let scratch = Scratch::new("list");
+    scratch.write("in/a.txt", "a");
+    let mut keys = Vec::new();
+    for (size, age) in [(1_000, 1), (10_000, 3), (100_000, 2)] {
+        let command = format!(
+            "rm -rf out && mkdir out && head -c {} /dev/zero > out/f",
+            size
+        );
+        let args = [
+            "--compression",
+            "none",
+            "--shell",
+            "in",
+            "out",
+            "--",
+            &command,
+        ];
+        scratch.run(args);
+        let key = scratch.key(&args);
+        scratch.backdate(&key, age * HOUR);
+        keys.push(key.replace('/', " "));
+    }
+    let (small, middle, large) = (keys[0].as_str(), keys[1].as_str(), keys[2].as_str());
+    let listed = |args: &[&str]| -> Vec<String> {
+        String::from_utf8(scratch.run(["list"].iter().chain(args)).stdout)
+            .unwrap()
+            .lines()
+            .map(|line| line[..small.len()].to_string())
+            .collect()
+    };
+
+    assert_eq!(listed(&[]), [large, middle, small]);
+    assert_eq!(listed(&["--sort", "size"]), [large, middle, small]);
+    assert_eq!(listed(&["--sort", "age"]), [middle, large, small]);
+    assert_eq!(listed(&["--min-size", "5000"]), [large, middle]);
+    assert_eq!(listed(&["--sort", "age", "--min-size", "50000"]), [large]);
+    assert_eq!(listed(&["--min-size", "0"]), [large, middle, small]);
+}
+
+#[test]
+fn missing_binaries_exit_like_a_shell_would() {
+    let scratch = Scratch::new("missing-binary");
+    scratch.write("in/a.txt", "a");
+    let output = scratch
+        .folca(["in", "out", "--", "folca-test-no-such-binary", "--flag"])
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(127));
+    assert!(
+        logs(&output).contains("command not found: folca-test-no-such-binary"),
+        "{}",
+        logs(&output)
+    );
+    assert!(!logs(&output).contains("exited with code"));
+    assert!(scratch.archives().is_empty());
+}
+
+#[test]
+fn set_environment_variables_reach_the_command_and_the_key() {
+    let scratch = Scratch::new("set-env");
+    scratch.write("in/a.txt", "a");
+    let command = "echo ran >> runs && mkdir -p out && printf %s \"$FOLCA_TEST_MODE\" > out/mode";
+    let with_mode = |mode: &str| {
+        let assignment = format!("FOLCA_TEST_MODE={}", mode);
+        scratch.run([
+            "--set-env",
+            &assignment,
+            "--shell",
+            "in",
+            "out",
+            "--",
+            command,
+        ]);
+        scratch.read("out/mode")
+    };
+
+    assert_eq!(with_mode("release"), "release");
+    assert_eq!(with_mode("debug=1"), "debug=1");
+    assert_eq!(scratch.runs(), 2);
+    std::fs::remove_dir_all(scratch.path("out")).unwrap();
+    assert_eq!(with_mode("release"), "release");
+    assert_eq!(scratch.runs(), 2);
+}
+
+#[test]
+fn outputs_inside_inputs_are_refused() {
+    let scratch = Scratch::new("overlap");
+    scratch.write("in/a.txt", "a");
+    let output = scratch
+        .folca([
+            "--shell",
+            "in",
+            "in/out",
+            "--",
+            "echo ran >> runs && mkdir -p in/out",
+        ])
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    assert!(
+        logs(&output).contains("overlaps input path"),
+        "{}",
+        logs(&output)
+    );
+    assert_eq!(scratch.runs(), 0);
+    assert!(!scratch.path("in/out").exists());
+    assert!(scratch.archives().is_empty());
+
+    let allowed = [
+        "--allow-overlap",
+        "--exclude",
+        "out",
+        "--shell",
+        "in",
+        "in/out",
+        "--",
+        "echo ran >> runs && mkdir -p in/out",
+    ];
+    scratch.run(allowed);
+    scratch.run(allowed);
+    assert_eq!(scratch.runs(), 1);
+}
+
+#[test]
+fn restored_outputs_keep_their_cached_mtime() {
+    let scratch = Scratch::new("preserve-mtime");
+    scratch.write("in/a.txt", "a");
+    let mtime = filetime::FileTime::from_unix_time(1_600_000_000, 123_456_789);
+    filetime::set_file_mtime(scratch.path("in/a.txt"), mtime).unwrap();
+    let restored_mtime = |args: &[&str]| {
+        let command = "echo ran >> runs && rm -rf out && cp -rp in out";
+        let args: Vec<&str> = args
+            .iter()
+            .copied()
+            .chain(["--shell", "in", "out", "--", command])
+            .collect();
+        scratch.run(&args);
+        std::fs::remove_dir_all(scratch.path("out")).unwrap();
+        scratch.run(&args);
+        let restored = scratch.path("out/a.txt").metadata().unwrap();
+        filetime::FileTime::from_last_modification_time(&restored)
+    };
+
+    assert_eq!(restored_mtime(&["--preserve-mtime"]), mtime);
+    // Tar headers only hold whole seconds, the flag is not part of the key so use another cache
+    assert_eq!(
+        restored_mtime(&["--cache-path", "seconds"]),
+        filetime::FileTime::from_unix_time(mtime.unix_seconds(), 0)
+    );
+    assert_eq!(scratch.runs(), 2);
+}
+
+#[test]
+fn touched_outputs_look_freshly_built() {
+    let scratch = Scratch::new("touch-output");
+    scratch.write("in/sub/a.txt", "a");
+    filetime::set_file_mtime(
+        scratch.path("in/sub/a.txt"),
+        filetime::FileTime::from_unix_time(1_600_000_000, 0),
+    )
+    .unwrap();
+    let args = [
+        "--shell",
+        "in",
+        "out",
+        "--",
+        "echo ran >> runs && rm -rf out && cp -rp in out",
+    ];
+    scratch.run(args);
+    std::fs::remove_dir_all(scratch.path("out")).unwrap();
+
+    let before = SystemTime::now() - Duration::from_secs(1);
+    scratch.run(["--touch-output"].iter().chain(&args));
+    let after = SystemTime::now() + Duration::from_secs(1);
+    assert_eq!(scratch.runs(), 1);
+    for restored in ["out/sub/a.txt", "out/sub"] {
+        let mtime = scratch
+            .path(restored)
+            .metadata()
+            .unwrap()
+            .modified()
+            .unwrap();
+        assert!(
+            before <= mtime && mtime <= after,
+            "{} is dated {:?}",
+            restored,
+            mtime
+        );
+    }
+}
+
+#[test]
+fn unwritable_caches_keep_the_command_result() {
+    let scratch = Scratch::new("unwritable-cache");
+    scratch.write("in/a.txt", "a");
+    let args = ["--shell", "in", "out", "--", COPY];
+    scratch.run(["--shell", "in", "other", "--", "mkdir -p other"]);
+    // Permissions do not stop root, a file where the entry's directory goes stops anyone
+    let command_hash = scratch.key(&args).split('/').next().unwrap().to_string();
+    scratch.write(&format!(".folca_cache/{}", command_hash), "in the way");
+    let cache = scratch.path(".folca_cache");
+    let mut permissions = cache.metadata().unwrap().permissions();
+    permissions.set_readonly(true);
+    std::fs::set_permissions(&cache, permissions.clone()).unwrap();
+
+    let output = scratch.folca(args).output().unwrap();
+    let strict = scratch
+        .folca(["--strict-cache"].iter().chain(&args))
+        .output()
+        .unwrap();
+    #[allow(clippy::permissions_set_readonly_false)]
+    permissions.set_readonly(false);
+    std::fs::set_permissions(&cache, permissions).unwrap();
+
+    assert!(output.status.success(), "folca failed: {}", logs(&output));
+    assert!(
+        logs(&output).contains("nothing was cached"),
+        "{}",
+        logs(&output)
+    );
+    assert_eq!(scratch.read("out/a.txt"), "a");
+    assert!(!strict.status.success());
+    assert_eq!(scratch.runs(), 2);
+    assert_eq!(scratch.archives().len(), 1);
+}
+
+#[test]
+fn cached_failures_are_replayed_without_running() {
+    let scratch = Scratch::new("cache-failures");
+    scratch.write("in/a.txt", "a");
+    let args = [
+        "--cache-failures",
+        "--capture",
+        "--shell",
+        "in",
+        "out",
+        "--",
+        "echo ran >> runs && echo broken >&2 && echo partial && exit 3",
+    ];
+    let first = scratch.folca(args).output().unwrap();
+    let second = scratch.folca(args).output().unwrap();
+
+    assert_eq!(scratch.runs(), 1);
+    for output in [&first, &second] {
+        assert_eq!(output.status.code(), Some(3));
+        assert!(String::from_utf8_lossy(&output.stdout).contains("partial\n"));
+        assert!(String::from_utf8_lossy(&output.stderr).contains("broken\n"));
+    }
+    assert!(logs(&second).contains("not running it again"));
+    assert!(!scratch.path("out").exists());
+
+    // Without the flag, the failure is run again to give it a chance to succeed
+    let uncached = scratch.folca(&args[1..]).output().unwrap();
+    assert_eq!(uncached.status.code(), Some(3));
+    assert_eq!(scratch.runs(), 2);
+}
+
+#[test]
+fn hits_and_misses_exit_with_the_configured_codes() {
+    let scratch = Scratch::new("exit-codes");
+    scratch.write("in/a.txt", "a");
+    let args = [
+        "--exit-code-hit",
+        "10",
+        "--exit-code-miss",
+        "11",
+        "--shell",
+        "in",
+        "out",
+        "--",
+        COPY,
+    ];
+    let exit_code = || scratch.folca(args).output().unwrap().status.code();
+
+    assert_eq!(exit_code(), Some(11));
+    assert_eq!(exit_code(), Some(10));
+    assert_eq!(scratch.runs(), 1);
+    // A failing command keeps its own exit code
+    let failed = scratch
+        .folca([
+            "--exit-code-miss",
+            "11",
+            "--shell",
+            "in",
+            "out",
+            "--",
+            "exit 3",
+        ])
+        .output()
+        .unwrap();
+    assert_eq!(failed.status.code(), Some(3));
+}
+
+#[test]
+fn incompressible_outputs_are_stored_uncompressed() {
+    let scratch = Scratch::new("compress-threshold");
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let random: Vec<u8> = (0..256 * 1024)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    std::fs::create_dir_all(scratch.path("random")).unwrap();
+    std::fs::write(scratch.path("random/data"), random).unwrap();
+    scratch.write("text/data", &words(50_000));
+
+    for (input, compression) in [("random", "none"), ("text", "gzip")] {
+        let command = format!("echo ran >> runs && rm -rf out && cp -r {} out", input);
+        let args = [
+            "--compress-threshold",
+            "0.9",
+            "--shell",
+            input,
+            "out",
+            "--",
+            &command,
+        ];
+        scratch.run(args);
+        let key = scratch.key(&args);
+        assert_eq!(
+            index_entry(&scratch.index(), &key)["compression"],
+            compression
+        );
+
+        std::fs::remove_dir_all(scratch.path("out")).unwrap();
+        scratch.run(args);
+        let original = std::fs::read(scratch.path(input).join("data")).unwrap();
+        assert!(std::fs::read(scratch.path("out/data")).unwrap() == original);
+    }
+    assert_eq!(scratch.runs(), 2);
+}
+
+#[test]
+fn command_files_give_the_inline_command_hash() {
+    let scratch = Scratch::new("command-file");
+    scratch.write("in/a.txt", "a");
+    let command = ["sh", "-c", "echo ran >> runs && rm -rf out && cp -r in out"];
+    scratch.write("command.txt", &(command.join("\n") + "\n"));
+    let inline = scratch.key(&["in", "out", "--", command[0], command[1], command[2]]);
+
+    assert_eq!(
+        scratch.key(&["--command-file", "command.txt", "in", "out"]),
+        inline
+    );
+    let mut from_stdin = scratch
+        .folca(["--print-key", "--command-file", "-", "in", "out"])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    from_stdin
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(command.join("\n").as_bytes())
+        .unwrap();
+    let output = from_stdin.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap().trim(), inline);
+
+    scratch.run(["--command-file", "command.txt", "in", "out"]);
+    scratch.run(["in", "out", "--", command[0], command[1], command[2]]);
+    assert_eq!(scratch.runs(), 1);
+}
+
+#[test]
+fn shell_commands_can_pipe() {
+    let scratch = Scratch::new("shell-pipe");
+    scratch.write("in/words.txt", "pear\napple\nfig\napple\n");
+    let args = [
+        "--shell",
+        "in",
+        "out",
+        "--",
+        "echo ran >> runs && mkdir -p out && sort in/words.txt | uniq -c | wc -l > out/count",
+    ];
+    scratch.run(args);
+    assert_eq!(scratch.read("out/count").trim(), "3");
+
+    std::fs::remove_dir_all(scratch.path("out")).unwrap();
+    scratch.run(args);
+    assert_eq!(scratch.runs(), 1);
+    assert_eq!(scratch.read("out/count").trim(), "3");
+    // Without the shell, `|` is an argument like any other
+    let unshelled = scratch
+        .folca(["in", "out2", "--", "echo", "a", "|", "wc"])
+        .output()
+        .unwrap();
+    assert!(String::from_utf8_lossy(&unshelled.stdout).contains("a | wc\n"));
+}
+
+#[test]
+fn verified_restores_rerun_on_tampered_outputs() {
+    let scratch = Scratch::new("verify-restore");
+    scratch.write("in/a.txt", "original");
+    let args = [
+        "--verify-restore",
+        "--compression",
+        "none",
+        "--shell",
+        "in",
+        "out",
+        "--",
+        COPY,
+    ];
+    scratch.run(args);
+    let key = scratch.key(&args);
+
+    // Tampered with consistently: a valid archive whose checksum matches the index
+    let archive = scratch.archives().remove(0);
+    let bytes = std::fs::read(&archive).unwrap();
+    let at = bytes
+        .windows(8)
+        .position(|window| window == b"original")
+        .unwrap();
+    let mut tampered = bytes.clone();
+    tampered[at..at + 8].copy_from_slice(b"tampered");
+    std::fs::remove_file(&archive).unwrap();
+    std::fs::write(&archive, &tampered).unwrap();
+    let mut index = scratch.index();
+    let entry = index["entries"]
+        .as_array_mut()
+        .unwrap()
+        .iter_mut()
+        .find(|entry| is_entry(entry, &key))
+        .unwrap();
+    entry["checksum"] = blake3::hash(&tampered).to_hex().as_str().into();
+    std::fs::write(
+        scratch.path(".folca_cache/index.json"),
+        serde_json::to_vec(&index).unwrap(),
+    )
+    .unwrap();
+
+    std::fs::remove_dir_all(scratch.path("out")).unwrap();
+    let output = scratch.run(args);
+    assert!(logs(&output).contains("Restored outputs differ from the cached entry"));
+    assert_eq!(scratch.runs(), 2);
+    assert_eq!(scratch.read("out/a.txt"), "original");
+}
+
+#[test]
+fn hit_counters_count_hits_and_misses() {
+    let scratch = Scratch::new("hit-counters");
+    scratch.write("in/a.txt", "a");
+    let args = ["--shell", "in", "out", "--", COPY];
+    scratch.run(args);
+    scratch.run(args);
+    // Neither counts: dry runs do not touch the cache, --print-key does not look into it
+    scratch
+        .folca(["--dry-run"].iter().chain(&args))
+        .output()
+        .unwrap();
+    scratch.key(&args);
+
+    let counters: serde_json::Value =
+        serde_json::from_slice(&scratch.run(["stats", "--hits", "--json"]).stdout).unwrap();
+    assert_eq!(counters["hits"], 1);
+    assert_eq!(counters["misses"], 1);
+    let archive_size = scratch.archives()[0].metadata().unwrap().len();
+    assert_eq!(counters["restored_bytes"], archive_size);
+
+    let text = String::from_utf8(scratch.run(["stats", "--hits"]).stdout).unwrap();
+    assert!(text.contains("Hit rate:    50.0%"), "{}", text);
+}
+
+#[test]
+fn ignoring_the_command_shares_entries_between_commands() {
+    let scratch = Scratch::new("ignore-command");
+    scratch.write("in/a.txt", "a");
+    let first = ["--ignore-command", "--shell", "in", "out", "--", COPY];
+    let second = [
+        "--ignore-command",
+        "--shell",
+        "in",
+        "out",
+        "--",
+        "echo other >> runs && rm -rf out && cp -r in out",
+    ];
+    assert_eq!(scratch.key(&first), scratch.key(&second));
+    assert_ne!(scratch.key(&first[1..]), scratch.key(&second[1..]));
+
+    scratch.run(first);
+    std::fs::remove_dir_all(scratch.path("out")).unwrap();
+    scratch.run(second);
+    assert_eq!(scratch.read("runs"), "ran\n");
+    assert_eq!(scratch.read("out/a.txt"), "a");
+    assert_eq!(scratch.archives().len(), 1);
+}
+
+#[test]
+fn namespaces_keep_entries_apart() {
+    let scratch = Scratch::new("namespace");
+    scratch.write("in/a.txt", "a");
+    let linux = ["--namespace", "linux", "--shell", "in", "out", "--", COPY];
+    let windows = ["--namespace", "windows", "--shell", "in", "out", "--", COPY];
+    let (linux_key, windows_key) = (scratch.key(&linux), scratch.key(&windows));
+    assert_ne!(linux_key, windows_key);
+
+    scratch.run(linux);
+    scratch.run(windows);
+    scratch.run(linux);
+    assert_eq!(scratch.runs(), 2);
+    assert_eq!(scratch.archives().len(), 2);
+    assert!(scratch.has_entry(&linux_key));
+    assert!(scratch.has_entry(&windows_key));
+}
+
+#[test]
+fn removing_the_last_entry_of_a_command_removes_its_directory() {
+    let scratch = Scratch::new("empty-command-dirs");
+    scratch.write("in/a.txt", "a");
+    let evicted = ["--shell", "in", "out", "--", COPY];
+    let kept = ["--shell", "in", "out", "--", "rm -rf out && cp -r in out"];
+    let command_dir = |args: &[&str]| {
+        let key = scratch.key(args);
+        scratch.path(&format!(".folca_cache/{}", key.split('/').next().unwrap()))
+    };
+    scratch.run(evicted);
+    assert!(command_dir(&evicted).is_dir());
+
+    scratch.run(["--max-entries", "1"].iter().chain(&kept));
+    assert!(!command_dir(&evicted).exists());
+    assert!(command_dir(&kept).is_dir());
+
+    scratch.run(["clear"]);
+    assert!(!command_dir(&kept).exists());
+    assert!(scratch.path(".folca_cache").is_dir());
+    assert!(scratch.archives().is_empty());
+}
+
+#[test]
+fn timed_out_commands_are_killed_and_not_cached() {
+    let scratch = Scratch::new("timeout");
+    scratch.write("in/a.txt", "a");
+    let start = std::time::Instant::now();
+    let output = scratch
+        .folca([
+            "--timeout",
+            "1s",
+            "--shell",
+            "in",
+            "out",
+            "--",
+            "mkdir -p out && sleep 30 && echo late > out/late",
+        ])
+        .output()
+        .unwrap();
+
+    assert!(start.elapsed() < Duration::from_secs(20));
+    assert_eq!(output.status.code(), Some(124), "{}", logs(&output));
+    assert!(logs(&output).contains("timed out"), "{}", logs(&output));
+    assert!(scratch.archives().is_empty());
+    assert!(!scratch.path("out/late").exists());
+}
+
+#[test]
+fn failed_commands_are_retried_and_their_success_cached() {
+    let scratch = Scratch::new("retries");
+    scratch.write("in/a.txt", "a");
+    let args = [
+        "--retries",
+        "2",
+        "--retry-delay",
+        "10ms",
+        "--shell",
+        "in",
+        "out",
+        "--",
+        "echo ran >> runs && if [ ! -e failed ]; then touch failed; exit 1; fi && rm -rf out && cp -r in out",
+    ];
+    let output = scratch.run(args);
+    assert!(logs(&output).contains("retrying"), "{}", logs(&output));
+    assert_eq!(scratch.runs(), 2);
+    assert_eq!(scratch.archives().len(), 1);
+
+    std::fs::remove_dir_all(scratch.path("out")).unwrap();
+    scratch.run(args);
+    assert_eq!(scratch.runs(), 2);
+    assert_eq!(scratch.read("out/a.txt"), "a");
+}
+
+#[test]
+fn restoring_more_than_the_limit_is_aborted() {
+    let scratch = Scratch::new("max-restore-size");
+    scratch.write("in/big.txt", &words(50_000));
+    let args = ["--shell", "in", "out", "--", COPY];
+    scratch.run(args);
+    std::fs::remove_dir_all(scratch.path("out")).unwrap();
+
+    let output = scratch.run(["--max-restore-size", "10KB"].iter().chain(&args));
+    assert!(
+        logs(&output).contains("refusing to restore it"),
+        "{}",
+        logs(&output)
+    );
+    assert_eq!(scratch.runs(), 2);
+    assert_eq!(scratch.read("out/big.txt"), words(50_000));
+
+    std::fs::remove_dir_all(scratch.path("out")).unwrap();
+    scratch.run(["--max-restore-size", "10MB"].iter().chain(&args));
+    assert_eq!(scratch.runs(), 2);
+
+    let error = zero_refused(&scratch, "--max-restore-size");
+    assert!(
+        error.contains("'--max-restore-size <max-restore-size>': Size cannot be zero"),
+        "{}",
+        error
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn fifos_in_the_input_do_not_block_hashing() {
+    let scratch = Scratch::new("fifo");
+    scratch.write("in/a.txt", "a");
+    let args = ["--shell", "in", "out", "--", COPY];
+    let skipped = ["--skip-special", "--shell", "in", "out", "--", COPY];
+    let without_fifo = scratch.key(&args);
+    let skipped_without_fifo = scratch.key(&skipped);
+    let status = Command::new("mkfifo")
+        .arg(scratch.path("in/pipe"))
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    // Nothing ever writes to the fifo, so opening it to hash it would hang
+    let key = |args: &[&str]| {
+        let mut child = scratch
+            .folca(std::iter::once("--print-key").chain(args.iter().copied()))
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let started = std::time::Instant::now();
+        while child.try_wait().unwrap().is_none() {
+            if started.elapsed() > Duration::from_secs(10) {
+                child.kill().unwrap();
+                panic!("hashing a fifo hung");
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    };
+    let with_fifo = key(&args);
+    assert_eq!(key(&args), with_fifo);
+    assert_ne!(with_fifo, without_fifo);
+    assert_eq!(key(&skipped), skipped_without_fifo);
+}
+
+#[test]
+fn printed_keys_are_the_keys_runs_use() {
+    let scratch = Scratch::new("print-key");
+    scratch.write("in/a.txt", "a");
+    scratch.write("in/skipped.log", "log");
+    let args = [
+        "--namespace",
+        "ci",
+        "--exclude",
+        "*.log",
+        "--env-key",
+        "FOLCA_TEST_FLAVOUR",
+        "--shell",
+        "in",
+        "out",
+        "--",
+        COPY,
+    ];
+    let key = |args: &[&str]| {
+        let output = scratch
+            .folca(std::iter::once("--print-key").chain(args.iter().copied()))
+            .env("FOLCA_TEST_FLAVOUR", "sweet")
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "folca failed: {}", logs(&output));
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    };
+    let printed = key(&args);
+    assert!(scratch.path(".folca_cache").read_dir().is_err());
+
+    let output = scratch
+        .folca(["--report", "report.json"].iter().chain(&args))
+        .env("FOLCA_TEST_FLAVOUR", "sweet")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "folca failed: {}", logs(&output));
+    assert!(scratch.has_entry(&printed));
+    index_entry(&scratch.index(), &printed);
+    let report: serde_json::Value = serde_json::from_str(&scratch.read("report.json")).unwrap();
+    let (command_hash, input_hash) = printed.split_once('/').unwrap();
+    assert_eq!(report["command_hash"], command_hash);
+    assert_eq!(report["input_hash"], input_hash);
+    assert_eq!(key(&args), printed);
+
+    for conflicting in [&["--print-key", "stats"][..], &["--print-key", "--gc"]] {
+        let output = scratch.folca(conflicting).output().unwrap();
+        assert_eq!(output.status.code(), Some(1), "{}", logs(&output));
+        assert!(logs(&output).contains("--print-key"), "{}", logs(&output));
+    }
+}
+
+#[test]
+fn relative_inputs_give_the_same_key_from_any_directory() {
+    let scratch = Scratch::new("relative-inputs");
+    scratch.write("project/in/src/main.c", "int main() {}\n");
+    scratch.write("project/sub/.keep", "");
+    copy_tree(&scratch.path("project"), &scratch.path("copy"));
+    let key_from = |cwd: &str, input: &str, output: &str| {
+        let output = scratch
+            .folca(["--print-key", input, output, "--", "make"])
+            .current_dir(scratch.path(cwd))
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "folca failed: {}", logs(&output));
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    let key = key_from("project", "in", "out");
+    assert_eq!(key_from("project/sub", "../in", "../out"), key);
+    assert_eq!(key_from("project/sub", "../in/", "../out"), key);
+    assert_eq!(key_from("copy", "in", "out"), key);
+}
+
+#[test]
+fn files_over_max_file_size_only_key_their_metadata() {
+    let scratch = Scratch::new("max-file-size");
+    scratch.write("in/big.bin", &"a".repeat(4096));
+    scratch.write("in/small.txt", "small");
+    let args = ["--max-file-size", "1KB", "in", "out", "--", "make"];
+    let key = scratch.key(&args);
+    let big = scratch.path("in/big.bin");
+    let mtime = filetime::FileTime::from_last_modification_time(&big.metadata().unwrap());
+
+    scratch.write("in/big.bin", &"b".repeat(4096));
+    filetime::set_file_mtime(&big, mtime).unwrap();
+    assert_eq!(scratch.key(&args), key);
+    // Without the flag the same change is seen
+    let unlimited = scratch.key(&args[2..]);
+    scratch.write("in/big.bin", &"a".repeat(4096));
+    filetime::set_file_mtime(&big, mtime).unwrap();
+    assert_ne!(scratch.key(&args[2..]), unlimited);
+    assert_eq!(scratch.key(&args), key);
+
+    scratch.write("in/big.bin", &"a".repeat(4097));
+    filetime::set_file_mtime(&big, mtime).unwrap();
+    assert_ne!(scratch.key(&args), key);
+    scratch.write("in/big.bin", &"a".repeat(4096));
+    filetime::set_file_mtime(&big, mtime).unwrap();
+    scratch.write("in/small.txt", "SMALL");
+    assert_ne!(scratch.key(&args), key);
+
+    let error = zero_refused(&scratch, "--max-file-size");
+    assert!(
+        error.contains("'--max-file-size <max-file-size>': Size cannot be zero"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn single_flight_runs_a_missing_key_once() {
+    let scratch = Scratch::new("single-flight");
+    scratch.write("in/a.txt", "a");
+    let slow_copy = "echo ran >> runs && sleep 1 && rm -rf out && cp -r in out";
+    let children: Vec<_> = (0..4)
+        .map(|_| {
+            scratch
+                .folca(["--single-flight", "--shell", "in", "out", "--", slow_copy])
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    for child in children {
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success(), "folca failed: {}", logs(&output));
+    }
+
+    assert_eq!(scratch.runs(), 1);
+    assert_eq!(scratch.archives().len(), 1);
+    assert_eq!(scratch.read("out/a.txt"), "a");
+}
+
+#[test]
+fn empty_output_directories_are_cached() {
+    let scratch = Scratch::new("empty-output");
+    scratch.write("in/a.txt", "a");
+    let args = [
+        "--shell",
+        "in",
+        "out",
+        "--",
+        "echo ran >> runs && mkdir -p out",
+    ];
+    scratch.run(args);
+    assert_eq!(scratch.archives().len(), 1);
+
+    std::fs::remove_dir_all(scratch.path("out")).unwrap();
+    scratch.run(args);
+    assert_eq!(scratch.runs(), 1);
+    let restored = std::fs::read_dir(scratch.path("out")).unwrap();
+    assert_eq!(restored.count(), 0);
+}
+
+#[test]
+fn cache_usage_is_logged_against_the_limit() {
+    let scratch = Scratch::new("cache-usage");
+    scratch.write("in/a.txt", &words(1000));
+    let args = [
+        "--max-cache-size",
+        "10MB",
+        "--shell",
+        "in",
+        "out",
+        "--",
+        COPY,
+    ];
+    scratch.run(args);
+    scratch.write("in/a.txt", &words(2000));
+    scratch.run(args);
+    let seeded: u64 = scratch
+        .archives()
+        .iter()
+        .map(|archive| archive.metadata().unwrap().len())
+        .sum();
+
+    let output = scratch.run(["-v"].iter().chain(&args));
+    assert_eq!(scratch.runs(), 2);
+    let expected = format!(
+        "Cache at {} of {}",
+        bytefmt::format(seeded),
+        bytefmt::format(10_000_000)
+    );
+    assert!(logs(&output).contains(&expected), "{}", logs(&output));
+}
+
+#[test]
+fn empty_input_directories_change_the_key() {
+    let scratch = Scratch::new("empty-input-dir");
+    scratch.write("in/a.txt", "a");
+    let args = ["in", "out", "--", "make"];
+    let key = scratch.key(&args);
+
+    std::fs::create_dir(scratch.path("in/empty")).unwrap();
+    let with_empty = scratch.key(&args);
+    assert_ne!(with_empty, key);
+    assert_eq!(scratch.key(&args), with_empty);
+    // Not the same as an empty file by the same name
+    std::fs::remove_dir(scratch.path("in/empty")).unwrap();
+    scratch.write("in/empty", "");
+    assert_ne!(scratch.key(&args), with_empty);
+    std::fs::remove_file(scratch.path("in/empty")).unwrap();
+    assert_eq!(scratch.key(&args), key);
+}
+
+#[test]
+fn dictionaries_shrink_small_archives_and_round_trip() {
+    let scratch = Scratch::new("dict");
+    // Typical of many small outputs: mostly boilerplate, which only a dictionary knows upfront
+    let manifest = |i: usize| {
+        format!(
+            "{{\"name\":\"package-{}\",\"version\":\"1.{}.0\",\"description\":\"{}\"}}\n",
+            i,
+            i,
+            words(150)
+        )
+    };
+    let samples: Vec<Vec<u8>> = (0..200).map(|i| manifest(i).into_bytes()).collect();
+    let dictionary = zstd::dict::from_samples(&samples, 4096).unwrap();
+    std::fs::write(scratch.path("dict.bin"), dictionary).unwrap();
+    scratch.write("in/package.json", &manifest(1000));
+    let args = |cache| {
+        [
+            "--cache-path",
+            cache,
+            "--compression",
+            "zstd",
+            "--shell",
+            "in",
+            "out",
+            "--",
+            COPY,
+        ]
+    };
+
+    scratch.run(args("plain"));
+    let with_dict = ["--dict", "dict.bin"];
+    scratch.run(with_dict.iter().chain(&args("dict")));
+    let size = |cache| scratch.archives_in(cache)[0].metadata().unwrap().len();
+    assert!(
+        size("dict") < size("plain"),
+        "{} >= {}",
+        size("dict"),
+        size("plain")
+    );
+
+    std::fs::remove_dir_all(scratch.path("out")).unwrap();
+    scratch.run(with_dict.iter().chain(&args("dict")));
+    assert_eq!(scratch.runs(), 2);
+    assert_eq!(scratch.read("out/package.json"), manifest(1000));
+}
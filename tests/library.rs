@@ -0,0 +1,80 @@
+//! Uses folca as a library, without spawning any process
+
+use folca::{compute_collision_guard, compute_key, store, try_restore, Config, Inventory};
+use std::ffi::OsString;
+use std::path::Path;
+
+fn config(dir: &Path) -> Config {
+    Config::parse_args([
+        OsString::from("folca"),
+        "--cache-path".into(),
+        dir.join("cache").into(),
+        dir.join("in").into(),
+        dir.join("out").into(),
+        "--".into(),
+        "build".into(),
+    ])
+    .unwrap()
+}
+
+#[test]
+fn outputs_stored_through_the_library_are_restored_through_it() {
+    let dir = std::env::temp_dir().join(format!("folca-test-{}-library", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("in")).unwrap();
+    std::fs::write(dir.join("in/source.txt"), "source").unwrap();
+    let config = config(&dir);
+    let key = compute_key(&config).unwrap();
+    assert_eq!(compute_key(&config).unwrap(), key);
+
+    let mut inventory = Inventory::open(&config).unwrap();
+    assert!(try_restore(&mut inventory, &config, &key, None)
+        .unwrap()
+        .is_none());
+    // Stands in for the command, which folca never runs here
+    std::fs::create_dir_all(dir.join("out/sub")).unwrap();
+    std::fs::write(dir.join("out/sub/built.txt"), "built").unwrap();
+    let size = store(&mut inventory, &config, &key, None, None).unwrap();
+    assert!(size > 0);
+
+    std::fs::remove_dir_all(dir.join("out")).unwrap();
+    let mut inventory = Inventory::open(&config).unwrap();
+    let hit = try_restore(&mut inventory, &config, &key, None)
+        .unwrap()
+        .expect("stored entry was not found");
+    assert_eq!(hit.archive_size, size);
+    assert!(hit.captured.is_none());
+    let restored = std::fs::read_to_string(dir.join("out/sub/built.txt"));
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(restored.unwrap(), "built");
+}
+
+#[test]
+fn entries_stored_for_other_inputs_under_the_same_key_are_misses() {
+    let dir = std::env::temp_dir().join(format!("folca-test-{}-collision", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("in")).unwrap();
+    std::fs::write(dir.join("in/source.txt"), "source").unwrap();
+    std::fs::create_dir_all(dir.join("out")).unwrap();
+    std::fs::write(dir.join("out/built.txt"), "built").unwrap();
+    let config = config(&dir);
+    let key = compute_key(&config).unwrap();
+    let stored_guard = compute_collision_guard(&config).unwrap();
+    let mut inventory = Inventory::open(&config).unwrap();
+    store(&mut inventory, &config, &key, None, Some(stored_guard)).unwrap();
+
+    // Other inputs, looked up as if their key had collided with the stored one
+    std::fs::write(dir.join("in/source.txt"), "other source").unwrap();
+    let other_guard = compute_collision_guard(&config).unwrap();
+    assert_ne!(other_guard, stored_guard);
+    std::fs::remove_dir_all(dir.join("out")).unwrap();
+    let collided = try_restore(&mut inventory, &config, &key, Some(other_guard)).unwrap();
+    assert!(collided.is_none());
+    assert!(!dir.join("out").exists());
+
+    let matching = try_restore(&mut inventory, &config, &key, Some(stored_guard)).unwrap();
+    let restored = std::fs::read_to_string(dir.join("out/built.txt"));
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(matching.is_some());
+    assert_eq!(restored.unwrap(), "built");
+}